@@ -6,6 +6,31 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink-ram.x");
 
     git();
+    build_info();
+    default_eid();
+}
+
+/// Optional build-time EID, used when no EID is stored in flash
+fn default_eid() {
+    println!("cargo::rerun-if-env-changed=USBNVME_DEFAULT_EID");
+    let Ok(eid) = std::env::var("USBNVME_DEFAULT_EID") else {
+        return;
+    };
+    let eid = eid.trim();
+    let eid = match eid.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => eid.parse(),
+    }
+    .unwrap_or_else(|_| panic!("Bad USBNVME_DEFAULT_EID {eid}"));
+    println!("cargo::rustc-env=USBNVME_DEFAULT_EID={eid}");
+}
+
+/// Target details for the GetBuildInfo vendor command
+fn build_info() {
+    let target = std::env::var("TARGET").unwrap();
+    let profile = std::env::var("PROFILE").unwrap();
+    println!("cargo::rustc-env=BUILD_TARGET={target}");
+    println!("cargo::rustc-env=BUILD_PROFILE={profile}");
 }
 
 fn git() {
@@ -34,6 +59,9 @@ fn git() {
     let dirty = if modified.is_empty() { "" } else { "-dirty" };
 
     println!("cargo::rustc-env=GIT_REV={rev}{dirty}");
+    // Separately for the GetBuildInfo vendor command
+    println!("cargo::rustc-env=GIT_REV_SHORT={rev}");
+    println!("cargo::rustc-env=GIT_DIRTY={}", !modified.is_empty() as u8);
 
     // Find git directory
     let path_res = Command::new("git")
@@ -230,6 +230,18 @@ SECTIONS
     *(.sram2_uninit .sram2_uninit.*);
     . = ALIGN(4); /* 4-byte align the end (VMA) of this section */
   } > SRAM2
+
+  /* ## Log, preserved across reset */
+  .log_uninit (NOLOAD) : ALIGN(4)
+  {
+    *(.log_uninit .log_uninit.*);
+  } > LOG_RAM
+
+  /* ## Panic message, preserved across reset */
+  .panic_uninit (NOLOAD) : ALIGN(4)
+  {
+    *(.panic_uninit .panic_uninit.*);
+  } > PANIC_RAM
 }
 
 /* Do not exceed this mark in the error messages below                                    | */
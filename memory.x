@@ -15,8 +15,17 @@ MEMORY
     /* SRAM3 can be used for DMA */
     SRAM3 : ORIGIN = 0x24040000, LENGTH =  64K
 
-    /* non-ECC. Used by bootloader, and sram2_unit usbnvme data */
-    SRAM2 : ORIGIN = 0x24020000, LENGTH =  128K
+    /* non-ECC. sram2_unit usbnvme data. Ends before LOG_RAM, followed
+     * by LOADER_RAM in xspiloader, which is overwritten at boot. */
+    SRAM2 : ORIGIN = 0x24020000, LENGTH =  96K - 256 - 4K
+    /* Before LOADER_RAM, preserved across reset for the previous boot's
+     * log. */
+    LOG_RAM : ORIGIN = 0x24036F00, LENGTH = 4K
+    /* End of SRAM2, preserved across reset for the last panic message.
+     * Not used by the bootloader. */
+    PANIC_RAM : ORIGIN = 0x2403FF00, LENGTH = 240
+    /* Boot reason from bootloader to usbnvme. Accessed by fixed address. */
+    BOOT_MAILBOX : ORIGIN = 0x2403FFF0, LENGTH = 16
 }
 
 STACK_SIZE = 64K;
@@ -0,0 +1,181 @@
+//! `xspiloader` boot slot confirmation.
+//!
+//! The loader boots a newly activated image slot once. Unless the image
+//! confirms it is working, the next reset reverts to the other slot.
+//! The record format is defined by `BootControl` in `xspiloader`.
+//!
+//! The slot that isn't running is used to stage a new image.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use deku::prelude::*;
+
+use crate::flash::{self, Flash, FlashError};
+use crate::stmutil::{BootMailbox, BootReason};
+use crate::{SharedFlash, SignalCS};
+
+// On-flash format. Flags are 0x00 when set, programmed without an erase.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct BootControlRecord {
+    magic: u32,
+    version: u8,
+    active: u8,
+    attempted: u8,
+    confirmed: u8,
+    reverted: u8,
+}
+
+const MAGIC: u32 = u32::from_le_bytes(*b"BCTL");
+const VERSION: u8 = 1;
+const RECORD_LEN: usize = 9;
+const OFF_CONFIRMED: u32 = 7;
+
+const FLAG_SET: u8 = 0x00;
+
+/// `xspiloader` image slot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::A),
+            1 => Some(Self::B),
+            _ => None,
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+}
+
+/// Reads the record, `None` if blank or invalid.
+fn read(flash: &mut Flash) -> Result<Option<BootControlRecord>, FlashError> {
+    let mut buf = [0u8; RECORD_LEN];
+    flash.read(flash::BOOT_CONTROL_REGION.start, &mut buf)?;
+
+    let Ok((_, rec)) = BootControlRecord::from_bytes((&buf, 0)) else {
+        return Ok(None);
+    };
+    // Includes blank 0xff flash
+    Ok((rec.magic == MAGIC && rec.version == VERSION).then_some(rec))
+}
+
+/// Returns the image slot that isn't running, for staging a new image.
+///
+/// The running slot is `booted` from the loader's boot mailbox if known,
+/// otherwise the slot the loader selects from the boot control record.
+/// Without a record the loader boots slot A.
+pub fn staging_slot(flash: &mut Flash, booted: Option<u8>) -> Slot {
+    let running = booted.and_then(Slot::from_u8).unwrap_or_else(|| {
+        let rec = read(flash).unwrap_or_else(|e| {
+            warn!("Boot control read failed: {e:?}");
+            None
+        });
+        match rec.and_then(|r| Some((Slot::from_u8(r.active)?, r.reverted))) {
+            Some((active, FLAG_SET)) => active.other(),
+            Some((active, _)) => active,
+            None => Slot::A,
+        }
+    });
+    running.other()
+}
+
+/// Returns whether the running image is on trial, the first boot of a
+/// newly activated slot that isn't yet confirmed.
+///
+/// The slot that isn't running then holds the fallback image, so it
+/// mustn't be used for staging until the running slot is confirmed.
+/// Unknown states are treated as a trial.
+pub fn running_trial(flash: &mut Flash, boot: Option<&BootMailbox>) -> bool {
+    if let Some(b) = boot {
+        return !matches!(
+            b.reason,
+            BootReason::Normal | BootReason::Reverted | BootReason::Fallback
+        );
+    }
+
+    match read(flash) {
+        Ok(Some(rec)) => {
+            rec.attempted == FLAG_SET
+                && rec.confirmed != FLAG_SET
+                && rec.reverted != FLAG_SET
+        }
+        Ok(None) => false,
+        Err(e) => {
+            warn!("Boot control read failed: {e:?}");
+            true
+        }
+    }
+}
+
+/// Confirms the booted image slot once `working` is signalled.
+///
+/// That is sent once USB is configured and an EID is assigned, so an
+/// image that fails before then is reverted by the loader.
+///
+/// `staging` is set as the staging slot once the running slot is
+/// confirmed, if deferred by `running_trial()`.
+#[embassy_executor::task]
+pub async fn confirm_task(
+    flash: &'static SharedFlash,
+    working: &'static SignalCS<()>,
+    staging: Option<Slot>,
+) {
+    working.wait().await;
+    let mut flash = flash.lock().await;
+    match confirm(&mut flash).await {
+        Ok(true) => {
+            if let Some(slot) = staging {
+                flash.set_staging(slot);
+            }
+        }
+        Ok(false) => (),
+        Err(e) => warn!("Failed confirming boot slot: {e:?}"),
+    }
+}
+
+/// Confirms a newly booted image slot.
+///
+/// Has no effect if the slot is already confirmed, or the loader has
+/// reverted to the other slot. Returns whether the slot is confirmed,
+/// or there is no boot control record.
+async fn confirm(flash: &mut Flash) -> Result<bool, FlashError> {
+    let Some(rec) = read(flash)? else {
+        debug!("No boot control");
+        return Ok(true);
+    };
+
+    if rec.confirmed == FLAG_SET {
+        debug!("Boot slot {} already confirmed", rec.active);
+        return Ok(true);
+    }
+
+    if rec.attempted != FLAG_SET || rec.reverted == FLAG_SET {
+        // Running from the other slot
+        info!("Boot slot {} not confirmed", rec.active);
+        return Ok(false);
+    }
+
+    flash
+        .program(
+            flash::BOOT_CONTROL_REGION.start + OFF_CONFIRMED,
+            &[FLAG_SET],
+        )
+        .await?;
+    info!("Confirmed boot slot {}", rec.active);
+    Ok(true)
+}
@@ -9,20 +9,37 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+use core::cell::RefCell;
 use core::num::Wrapping;
+use core::sync::atomic::Ordering;
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
 use deku::prelude::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, TimeoutError, Timer};
+use log::LevelFilter;
 use mctp::{
-    AsyncListener, AsyncReqChannel, AsyncRespChannel, Eid, Error, Result,
+    AsyncListener, AsyncReqChannel, AsyncRespChannel, Eid, Error, MsgIC, Result,
 };
+use mctp_estack::router::RouterAsyncListener;
 
-use crate::SignalCS;
+use crate::multilog::{self, MultiLog};
+use crate::settings::VendorAllowList;
+use crate::{lastpanic, Routes, SignalCS};
 
 pub struct MctpBench<'a> {
     buf: &'a mut [u8],
+    /// Pattern currently in `buf`
+    pattern: BenchPattern,
+    sent: u64,
+    /// Reply timeouts and mismatches in the current or last bench
+    errors: u32,
+    /// Start of the current or last bench
+    start: Instant,
 }
 
 impl<'a> MctpBench<'a> {
@@ -33,46 +50,355 @@ impl<'a> MctpBench<'a> {
     const COMMAND_MAGIC: u16 = 0x22dd;
     const COMMAND_VERSION: u8 = 1;
 
-    const BENCH_HEADER_LEN: usize = 9;
+    pub const BENCH_HEADER_LEN: usize = 9;
+
+    /// Maximum response payload, following the status byte
+    const RESPONSE_PAYLOAD_MAX: usize = 512;
+
+    /// Attest nonce length limits. The request fits in one baseline MTU
+    /// packet.
+    const ATTEST_NONCE_MIN: usize = 16;
+    const ATTEST_NONCE_MAX: usize = 48;
+
+    /// Flag in `CommandRequestBench` for latency mode
+    const FLAG_LATENCY: u32 = 1 << 0;
+    /// Flag in `CommandRequestBench` for an inter-message delay
+    const FLAG_DELAY: u32 = 1 << 1;
+    /// Flag in `CommandRequestBench` for echo mode
+    const FLAG_ECHO: u32 = 1 << 2;
+    /// `BenchPattern` field in `CommandRequestBench` flags
+    const FLAG_PATTERN_SHIFT: u32 = 8;
+    const FLAG_PATTERN_MASK: u32 = 0xf << Self::FLAG_PATTERN_SHIFT;
+
+    /// Time to wait for a reply in latency or echo mode
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Interval between send attempts while no tag is free for the peer
+    const TAG_BACKOFF: Duration = Duration::from_millis(200);
+    /// Time to keep retrying for a free tag. Longer than the mctp-estack
+    /// flow expiry, so that expiring tags become free.
+    const TAG_WAIT: Duration = Duration::from_secs(7);
+
+    /// Messages between checking whether to log progress
+    const PROGRESS_CHECK: u64 = 1024;
+    const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Time to wait for the bench task to acknowledge a stop
+    const STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Time between a Reboot response and resetting, to allow it to send
+    const REBOOT_DELAY: Duration = Duration::from_millis(100);
+
+    /// Maximum payload for a `FragmentTest` response
+    #[cfg(feature = "mctp-frag-test")]
+    const FRAGMENT_TEST_MAX_LEN: usize = 1024;
 
     pub fn new(buf: &'a mut [u8]) -> Result<Self> {
         if buf.len() < Self::BENCH_HEADER_LEN {
             return Err(Error::BadArgument);
         }
-        for (i, b) in buf.iter_mut().enumerate() {
-            *b = (i & 0xff) as u8;
+        let mut s = Self {
+            buf,
+            pattern: BenchPattern::Ramp,
+            sent: 0,
+            errors: 0,
+            start: Instant::now(),
+        };
+        s.fill(BenchPattern::Ramp);
+        Ok(s)
+    }
+
+    /// Fills the buffer with a payload pattern, and the message header.
+    ///
+    /// The sequence number is written for each message by `send()`.
+    fn fill(&mut self, pattern: BenchPattern) {
+        for (b, p) in self.buf.iter_mut().zip(pattern.bytes()) {
+            *b = p;
         }
-        buf[..3].copy_from_slice(&Self::VENDOR_SUBTYPE);
-        buf[3..5].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        self.buf[..3].copy_from_slice(&Self::VENDOR_SUBTYPE);
+        self.buf[3..5].copy_from_slice(&Self::MAGIC.to_le_bytes());
+        self.pattern = pattern;
+    }
 
-        Ok(Self { buf })
+    /// Returns the number of messages sent by the current or last bench.
+    pub fn sent(&self) -> u64 {
+        self.sent
     }
 
+    /// Sends a `BenchResult` summary of the current or last bench, if
+    /// it was requested by RequestBench.
+    ///
+    /// The summary is a new request message on `req`, since the
+    /// requester's tag was used by the RequestBench response. `req`
+    /// should be a new channel, as the bench channel's tag may have
+    /// been released if the bench was stopped.
+    pub async fn send_result(
+        &self,
+        req: &mut impl AsyncReqChannel,
+        bench: &BenchRequest,
+        status: BenchStatus,
+    ) -> Result<()> {
+        let Some(iid) = bench.iid else {
+            return Ok(());
+        };
+        let elapsed_ms = self.start.elapsed().as_millis();
+        let bytes = self.sent * bench.len as u64;
+        let res = BenchResult {
+            status: status as u8,
+            messages: self.sent,
+            bytes,
+            elapsed_ms,
+            // bytes per ms is kB/s
+            kbps: bytes.checked_div(elapsed_ms).unwrap_or(0),
+            errors: self.errors,
+        };
+
+        let cmd = MctpBenchCommandMsg {
+            vendor_prefix: Self::VENDOR_SUBTYPE,
+            magic: Self::COMMAND_MAGIC,
+            version: Self::COMMAND_VERSION,
+            command: CommandCode::BenchResult as u8,
+            iid,
+        };
+        let mut buf = [0u8; 13 + BenchResult::LEN];
+        let l = cmd.to_slice(&mut buf).map_err(|_| Error::NoSpace)?;
+        let p = res.to_slice(&mut buf[l..]).map_err(|_| Error::NoSpace)?;
+        debug_assert_eq!(p, BenchResult::LEN);
+        debug!("mctp-bench result {res:?}");
+        req.send(mctp::MCTP_TYPE_VENDOR_PCIE, &buf[..l + p]).await
+    }
+
+    /// Sends bench messages.
+    ///
+    /// In latency mode each message waits for a reply from the peer,
+    /// and the round trip time histogram is logged at the end.
+    /// `req` must have a non-expiring tag.
     pub async fn send(
         &mut self,
         req: &mut impl AsyncReqChannel,
-        count: u64,
-        len: usize,
+        bench: &BenchRequest,
     ) -> Result<()> {
-        if len < 9 {
+        if bench.len < 9 || bench.len > self.buf.len() {
             return Err(Error::BadArgument);
         }
-        let buf = self.buf.get_mut(..len).ok_or(Error::BadArgument)?;
+        if bench.pattern != self.pattern {
+            self.fill(bench.pattern);
+        }
+        let buf = &mut self.buf[..bench.len];
+
+        let mut hist = bench.latency.then(LatencyHistogram::default);
+
+        self.sent = 0;
+        self.errors = 0;
+        self.start = Instant::now();
+        let mut progress_start = Instant::now();
+        let mut progress_sent = 0;
 
         let mut counter = Wrapping(Self::SEQ_START);
-        for _ in 0..count {
+        for _ in 0..bench.count {
+            Self::progress(
+                self.sent,
+                bench,
+                &mut progress_start,
+                &mut progress_sent,
+            );
+
             buf[5..9].copy_from_slice(&counter.0.to_le_bytes());
             counter += 1;
 
-            req.send(mctp::MCTP_TYPE_VENDOR_PCIE, buf).await?;
+            let start = Instant::now();
+            Self::send_retry(req, buf).await?;
+            self.sent += 1;
+
+            if let Some(hist) = hist.as_mut() {
+                // Only the arrival of a reply matters, the content
+                // is discarded. A long reply fails with NoSpace.
+                let mut reply = [0u8; 16];
+                match with_timeout(Self::REPLY_TIMEOUT, req.recv(&mut reply))
+                    .await
+                {
+                    Ok(Ok(_)) | Ok(Err(Error::NoSpace)) => {
+                        hist.record(start.elapsed())
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(TimeoutError) => {
+                        hist.timeouts += 1;
+                        self.errors = self.errors.saturating_add(1);
+                    }
+                }
+            }
+
+            // After the reply, so it isn't counted in the round trip
+            if let Some(delay) = bench.delay {
+                Timer::after(delay).await;
+            }
+        }
+
+        if let Some(hist) = hist {
+            hist.log();
         }
         Ok(())
     }
 
+    /// Sends bench messages in echo mode.
+    ///
+    /// Each message is sent on a new channel from `new_req`, with an
+    /// expiring tag, and waits for the peer to echo it back. Replies are
+    /// checked against the sent message. Reply counts and the round trip
+    /// time histogram are logged at the end.
+    pub async fn send_echo<R: AsyncReqChannel>(
+        &mut self,
+        mut new_req: impl FnMut() -> R,
+        bench: &BenchRequest,
+    ) -> Result<()> {
+        if bench.len < 9 || bench.len > self.buf.len() {
+            return Err(Error::BadArgument);
+        }
+        if bench.pattern != self.pattern {
+            self.fill(bench.pattern);
+        }
+
+        let mut hist = LatencyHistogram::default();
+        let mut stats = EchoStats::default();
+
+        self.sent = 0;
+        self.errors = 0;
+        self.start = Instant::now();
+        let mut progress_start = Instant::now();
+        let mut progress_sent = 0;
+
+        let mut counter = Wrapping(Self::SEQ_START);
+        let r: Result<()> = async {
+            for _ in 0..bench.count {
+                Self::progress(
+                    self.sent,
+                    bench,
+                    &mut progress_start,
+                    &mut progress_sent,
+                );
+
+                let seq = counter.0.to_le_bytes();
+                self.buf[5..9].copy_from_slice(&seq);
+                counter += 1;
+
+                let mut req = new_req();
+                let start = Instant::now();
+                Self::send_retry(&mut req, &self.buf[..bench.len]).await?;
+                self.sent += 1;
+
+                // The reply is received in place of the sent message,
+                // which is restored if they differ.
+                let reply =
+                    with_timeout(Self::REPLY_TIMEOUT, req.recv(&mut *self.buf))
+                        .await;
+                let matched = match reply {
+                    Ok(Ok((typ, _ic, reply))) => {
+                        hist.record(start.elapsed());
+                        stats.reply_bytes += reply.len() as u64;
+                        Some(
+                            typ == mctp::MCTP_TYPE_VENDOR_PCIE
+                                && reply.len() == bench.len
+                                && reply[..3] == Self::VENDOR_SUBTYPE
+                                && reply[3..5] == Self::MAGIC.to_le_bytes()
+                                && reply[5..9] == seq
+                                && self.pattern.mismatch(reply).is_none(),
+                        )
+                    }
+                    // Longer than the buffer
+                    Ok(Err(Error::NoSpace)) => {
+                        hist.record(start.elapsed());
+                        Some(false)
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(TimeoutError) => None,
+                };
+                match matched {
+                    Some(true) => stats.matched += 1,
+                    Some(false) => {
+                        stats.mismatched += 1;
+                        self.errors = self.errors.saturating_add(1);
+                        self.fill(self.pattern);
+                    }
+                    None => {
+                        // The buffer is unchanged
+                        hist.timeouts += 1;
+                        stats.missing += 1;
+                        self.errors = self.errors.saturating_add(1);
+                    }
+                }
+
+                if let Some(delay) = bench.delay {
+                    Timer::after(delay).await;
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        stats.log();
+        hist.log();
+        r
+    }
+
+    /// Sends a bench message, backing off while the router has no free
+    /// tag for the peer.
+    ///
+    /// Tags held by unanswered requests are freed as they expire, so this
+    /// gives up after `TAG_WAIT`.
+    async fn send_retry(
+        req: &mut impl AsyncReqChannel,
+        msg: &[u8],
+    ) -> Result<()> {
+        let deadline = Instant::now() + Self::TAG_WAIT;
+        let mut warned = false;
+        loop {
+            match req.send(mctp::MCTP_TYPE_VENDOR_PCIE, msg).await {
+                Err(Error::TagUnavailable) if Instant::now() < deadline => {
+                    if !warned {
+                        warn!("mctp-bench no free tag, retrying");
+                        warned = true;
+                    }
+                    Timer::after(Self::TAG_BACKOFF).await;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Logs send progress every `PROGRESS_INTERVAL`.
+    fn progress(
+        sent: u64,
+        bench: &BenchRequest,
+        progress_start: &mut Instant,
+        progress_sent: &mut u64,
+    ) {
+        // Only check the clock occasionally, to keep the loop cheap
+        if !sent.is_multiple_of(Self::PROGRESS_CHECK) {
+            return;
+        }
+        let elapsed = progress_start.elapsed();
+        if elapsed >= Self::PROGRESS_INTERVAL {
+            let bytes = (sent - *progress_sent) * bench.len as u64;
+            // bytes per ms is kB/s
+            info!(
+                "mctp-bench sent {}/{}, {} kB/s",
+                sent,
+                bench.count,
+                bytes / elapsed.as_millis()
+            );
+            *progress_start = Instant::now();
+            *progress_sent = sent;
+        }
+    }
+
     pub async fn handle_request(
         msg: &[u8],
         resp: &mut impl AsyncRespChannel,
-        bench_request: &SignalCS<BenchRequest>,
+        #[cfg_attr(not(feature = "mctp-frag-test"), allow(unused))]
+        routes: &Routes,
+        bench_control: &BenchControl,
+        logger: &MultiLog,
+        vendor_allow: Option<&[Eid]>,
     ) -> Result<()> {
         let Ok(((rest, _), cmd)) = MctpBenchCommandMsg::from_bytes((msg, 0))
         else {
@@ -90,22 +416,78 @@ impl<'a> MctpBench<'a> {
 
         let req_cmd = CommandCode::from_u8(cmd.command);
 
-        let resp_code = if let Some(req_cmd) = req_cmd {
+        let peer = resp.remote_eid();
+        if let (Some(c), Some(allow)) = (&req_cmd, vendor_allow) {
+            if c.privileged() && !allow.contains(&peer) {
+                warn!("Rejected vendor command {c:?} from eid {peer}");
+                return Self::respond(
+                    cmd,
+                    CommandResponse::NotPermitted,
+                    &[],
+                    resp,
+                )
+                .await;
+            }
+        }
+
+        #[cfg(feature = "mctp-frag-test")]
+        if let Some(CommandCode::FragmentTest) = req_cmd {
+            return Self::fragment_test(cmd, rest, resp, routes).await;
+        }
+
+        let reboot = matches!(
+            req_cmd,
+            Some(CommandCode::Reboot | CommandCode::RebootToLoader)
+        );
+        let to_loader = matches!(req_cmd, Some(CommandCode::RebootToLoader));
+
+        let mut payload = [0u8; Self::RESPONSE_PAYLOAD_MAX];
+        let (resp_code, payload) = if let Some(req_cmd) = req_cmd {
             match Self::handle_command(
                 req_cmd,
                 rest,
-                bench_request,
-                resp.remote_eid(),
+                &mut payload,
+                bench_control,
+                logger,
+                peer,
+                cmd.iid,
             )
             .await
             {
-                Ok(()) => CommandResponse::Success,
-                Err(e) => e,
+                Ok(l) => (CommandResponse::Success, &payload[..l]),
+                Err(e) => (e, &payload[..0]),
             }
         } else {
-            CommandResponse::UnknownCommand
+            (CommandResponse::UnknownCommand, &payload[..0])
         };
 
+        let reboot = reboot && matches!(resp_code, CommandResponse::Success);
+        let r = Self::respond(cmd, resp_code, payload, resp).await;
+
+        if reboot {
+            if to_loader {
+                info!("Rebooting to loader");
+            } else {
+                info!("Rebooting");
+            }
+            Timer::after(Self::REBOOT_DELAY).await;
+            if to_loader {
+                crate::stmutil::reboot_to_loader();
+            }
+            crate::stmutil::reboot();
+        }
+        r
+    }
+
+    /// Sends a command response.
+    ///
+    /// The body is a status byte followed by `payload`.
+    async fn respond(
+        cmd: MctpBenchCommandMsg,
+        code: CommandResponse,
+        payload: &[u8],
+        resp: &mut impl AsyncRespChannel,
+    ) -> Result<()> {
         // Response has mostly the same parameters as the request cmd
         let r = MctpBenchCommandMsg {
             command: CommandCode::Response as u8,
@@ -114,19 +496,74 @@ impl<'a> MctpBench<'a> {
 
         let mut buf = [0u8; 13];
         let l = r.to_slice(&mut buf).unwrap();
-        // body is a single status byte
-        buf[l] = resp_code as u8;
+        buf[l] = code as u8;
         let buf = &buf[..l + 1];
 
-        resp.send(buf).await
+        resp.send_vectored(MsgIC(false), &[buf, payload]).await
     }
 
+    /// Responds with a ramp payload, sent with forced fragmentation.
+    ///
+    /// Used to test a peer's handling of non-default packet sizes.
+    #[cfg(feature = "mctp-frag-test")]
+    async fn fragment_test(
+        cmd: MctpBenchCommandMsg,
+        body: &[u8],
+        resp: &mut impl AsyncRespChannel,
+        routes: &Routes,
+    ) -> Result<()> {
+        let Ok(((rest, _), req)) = CommandFragmentTest::from_bytes((body, 0))
+        else {
+            trace!("Short fragment test request");
+            return Self::respond(cmd, CommandResponse::Error, &[], resp).await;
+        };
+        if !rest.is_empty() {
+            trace!("Long fragment test request");
+            return Self::respond(cmd, CommandResponse::Error, &[], resp).await;
+        }
+
+        let mtu = req.packet_size as usize;
+        let len = req.payload_size as usize;
+        if !(crate::MCTP_BASELINE_MTU + crate::MCTP_HEADER..=crate::USB_MTU)
+            .contains(&mtu)
+            || len > Self::FRAGMENT_TEST_MAX_LEN
+        {
+            trace!("Bad fragment test request {req:?}");
+            return Self::respond(cmd, CommandResponse::BadArgument, &[], resp)
+                .await;
+        }
+
+        let mut payload = [0u8; Self::FRAGMENT_TEST_MAX_LEN];
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b = (i & 0xff) as u8;
+        }
+
+        let dest = resp.remote_eid();
+        // Only applies to this requester, one test at a time
+        if !routes.set_fragment_override(dest, mtu) {
+            debug!("Fragment test already running");
+            return Self::respond(cmd, CommandResponse::Busy, &[], resp).await;
+        }
+        info!("Fragment test to eid {dest}, packet size {mtu}, payload {len}");
+        let r =
+            Self::respond(cmd, CommandResponse::Success, &payload[..len], resp)
+                .await;
+        routes.clear_fragment_override();
+        r
+    }
+
+    /// Handles a command.
+    ///
+    /// Returns the length of the response payload written to `payload`.
     async fn handle_command(
         cmd: CommandCode,
         body: &[u8],
-        bench_request: &SignalCS<BenchRequest>,
+        payload: &mut [u8],
+        bench_control: &BenchControl,
+        logger: &MultiLog,
         peer: Eid,
-    ) -> core::result::Result<(), CommandResponse> {
+        iid: u32,
+    ) -> core::result::Result<usize, CommandResponse> {
         match cmd {
             CommandCode::RequestBench => {
                 let Ok(((rest, _), req)) =
@@ -144,19 +581,242 @@ impl<'a> MctpBench<'a> {
                     trace!("Requested payload too short");
                     return Err(CommandResponse::BadArgument);
                 }
+                let pattern = (req.flags & Self::FLAG_PATTERN_MASK)
+                    >> Self::FLAG_PATTERN_SHIFT;
+                let Some(pattern) = BenchPattern::from_u32(pattern) else {
+                    trace!("Unknown bench pattern {pattern}");
+                    return Err(CommandResponse::BadArgument);
+                };
 
-                bench_request.signal(BenchRequest {
+                let r = BenchRequest {
                     count: req.message_count,
                     len: req.payload_size as usize,
                     dest: peer,
-                })
+                    iid: Some(iid),
+                    latency: req.flags & Self::FLAG_LATENCY != 0,
+                    echo: req.flags & Self::FLAG_ECHO != 0,
+                    delay: (req.delay_us != 0)
+                        .then(|| Duration::from_micros(req.delay_us as u64)),
+                    pattern,
+                };
+                if !bench_control.start(r) {
+                    debug!("All bench slots busy");
+                    return Err(CommandResponse::Busy);
+                }
             }
-            CommandCode::Response => {
+            CommandCode::Response | CommandCode::BenchResult => {
                 trace!("Response as request");
                 return Err(CommandResponse::Error);
             }
+            CommandCode::StopBench => {
+                if !body.is_empty() {
+                    trace!("Long stop request");
+                    return Err(CommandResponse::Error);
+                }
+
+                // Stops the bench to the requester
+                let sent = if let Some(slot) = bench_control.find(peer) {
+                    slot.stopped.reset();
+                    slot.stop.signal(());
+                    let Ok(sent) =
+                        with_timeout(Self::STOP_TIMEOUT, slot.stopped.wait())
+                            .await
+                    else {
+                        debug!("Bench task didn't respond to stop");
+                        return Err(CommandResponse::Error);
+                    };
+                    sent
+                } else {
+                    0
+                };
+                let r = CommandStopBenchResponse { sent };
+                return r.to_slice(payload).map_err(|_| CommandResponse::Error);
+            }
+            CommandCode::SetLogLevel => {
+                let Ok(((rest, _), req)) =
+                    CommandSetLogLevel::from_bytes((body, 0))
+                else {
+                    trace!("Short log level request");
+                    return Err(CommandResponse::Error);
+                };
+
+                let level = LevelFilter::iter().nth(req.level as usize);
+                let prev = if rest.is_empty() {
+                    // Global level
+                    let Some(level) = level else {
+                        trace!("Bad log level {}", req.level);
+                        return Err(CommandResponse::BadArgument);
+                    };
+                    let prev = multilog::level();
+                    multilog::set_level(level);
+                    info!("Log level {prev} -> {level}");
+                    prev as u8
+                } else {
+                    // Module override
+                    if level.is_none()
+                        && req.level != CommandSetLogLevel::LEVEL_NONE
+                    {
+                        trace!("Bad log level {}", req.level);
+                        return Err(CommandResponse::BadArgument);
+                    }
+                    let Ok(module) = core::str::from_utf8(rest) else {
+                        trace!("Bad log module");
+                        return Err(CommandResponse::BadArgument);
+                    };
+                    if module == multilog::SERIAL_TARGET {
+                        let prev = multilog::set_serial_level(level);
+                        let level = multilog::serial_level();
+                        info!("Serial log level {prev} -> {level}");
+                        let r = CommandSetLogLevel { level: prev as u8 };
+                        return r
+                            .to_slice(payload)
+                            .map_err(|_| CommandResponse::Error);
+                    }
+                    let Ok(prev) = multilog::set_module_level(module, level)
+                    else {
+                        trace!("Can't set log level for {module}");
+                        return Err(CommandResponse::BadArgument);
+                    };
+                    info!("Log level {module} {prev:?} -> {level:?}");
+                    prev.map(|p| p as u8)
+                        .unwrap_or(CommandSetLogLevel::LEVEL_NONE)
+                };
+
+                let r = CommandSetLogLevel { level: prev };
+                return r.to_slice(payload).map_err(|_| CommandResponse::Error);
+            }
+            CommandCode::GetDiagnostics => {
+                if !body.is_empty() {
+                    trace!("Long diagnostics request");
+                    return Err(CommandResponse::Error);
+                }
+
+                let stats = &crate::usb::USB_STATS;
+                let d = Diagnostics {
+                    usb_speed: crate::usb::link_speed() as u8,
+                    usb_configured: stats.configured.load(Ordering::Relaxed)
+                        as u8,
+                    usb_resets: stats.resets.load(Ordering::Relaxed),
+                    usb_suspends: stats.suspends.load(Ordering::Relaxed),
+                    usb_resumes: stats.resumes.load(Ordering::Relaxed),
+                    supply_mv: crate::stmutil::supply_millivolts()
+                        .await
+                        .map_or(0, |mv| mv.min(u16::MAX as u32) as u16),
+                    reassembly_expired: crate::reassembly::REASSEMBLY.expired(),
+                };
+                return d.to_slice(payload).map_err(|_| CommandResponse::Error);
+            }
+            CommandCode::GetStatus => {
+                if !body.is_empty() {
+                    trace!("Long status request");
+                    return Err(CommandResponse::Error);
+                }
+
+                let st = Status {
+                    uptime_ms: Instant::now().as_millis(),
+                    uuid: *crate::device_uuid().as_bytes(),
+                };
+                let l =
+                    st.to_slice(payload).map_err(|_| CommandResponse::Error)?;
+                let product = crate::PRODUCT.as_bytes();
+                payload
+                    .get_mut(l..l + product.len())
+                    .ok_or(CommandResponse::Error)?
+                    .copy_from_slice(product);
+                return Ok(l + product.len());
+            }
+            CommandCode::GetBuildInfo => {
+                if !body.is_empty() {
+                    trace!("Long build info request");
+                    return Err(CommandResponse::Error);
+                }
+
+                let b = BuildInfo::new();
+                let l =
+                    b.to_slice(payload).map_err(|_| CommandResponse::Error)?;
+                let target = env!("BUILD_TARGET").as_bytes();
+                payload
+                    .get_mut(l..l + target.len())
+                    .ok_or(CommandResponse::Error)?
+                    .copy_from_slice(target);
+                return Ok(l + target.len());
+            }
+            CommandCode::Attest => {
+                if !(Self::ATTEST_NONCE_MIN..=Self::ATTEST_NONCE_MAX)
+                    .contains(&body.len())
+                {
+                    trace!("Bad attestation nonce length {}", body.len());
+                    return Err(CommandResponse::BadArgument);
+                }
+
+                let r = AttestResponse {
+                    uuid: *crate::device_uuid().as_bytes(),
+                    mac: crate::attestation_mac(body),
+                };
+                return r.to_slice(payload).map_err(|_| CommandResponse::Error);
+            }
+            CommandCode::GetLastPanic => {
+                if !body.is_empty() {
+                    trace!("Long last panic request");
+                    return Err(CommandResponse::Error);
+                }
+                return Ok(lastpanic::previous(payload));
+            }
+            CommandCode::GetLog => {
+                let Ok(((rest, _), req)) = CommandGetLog::from_bytes((body, 0))
+                else {
+                    trace!("Short log request");
+                    return Err(CommandResponse::Error);
+                };
+                if !rest.is_empty() {
+                    trace!("Long log request");
+                    return Err(CommandResponse::Error);
+                }
+
+                let (hdr, text) =
+                    payload.split_at_mut(CommandGetLogResponse::LEN);
+                let (first, next, l) = logger.ring_read(req.start, text);
+                let r = CommandGetLogResponse { first, next };
+                let h = r.to_slice(hdr).map_err(|_| CommandResponse::Error)?;
+                debug_assert_eq!(h, CommandGetLogResponse::LEN);
+                return Ok(h + l);
+            }
+            CommandCode::GetPreviousLog => {
+                let Ok(((rest, _), req)) =
+                    CommandGetPreviousLog::from_bytes((body, 0))
+                else {
+                    trace!("Short previous log request");
+                    return Err(CommandResponse::Error);
+                };
+                if !rest.is_empty() {
+                    trace!("Long previous log request");
+                    return Err(CommandResponse::Error);
+                }
+
+                let (hdr, text) =
+                    payload.split_at_mut(CommandGetPreviousLogResponse::LEN);
+                let (total, l) =
+                    logger.previous_read(req.offset as usize, text);
+                let r = CommandGetPreviousLogResponse {
+                    total: total as u32,
+                };
+                let h = r.to_slice(hdr).map_err(|_| CommandResponse::Error)?;
+                debug_assert_eq!(h, CommandGetPreviousLogResponse::LEN);
+                return Ok(h + l);
+            }
+            CommandCode::Reboot | CommandCode::RebootToLoader => {
+                if !body.is_empty() {
+                    trace!("Long reboot request");
+                    return Err(CommandResponse::Error);
+                }
+                // Reboots after responding
+            }
+            // Handled separately when enabled
+            CommandCode::FragmentTest => {
+                return Err(CommandResponse::UnknownCommand);
+            }
         }
-        Ok(())
+        Ok(0)
     }
 }
 
@@ -165,6 +825,42 @@ impl<'a> MctpBench<'a> {
 enum CommandCode {
     Response = 0x00,
     RequestBench = 0x01,
+    StopBench = 0x02,
+    GetDiagnostics = 0x03,
+    SetLogLevel = 0x04,
+    GetLastPanic = 0x05,
+    GetLog = 0x06,
+    Reboot = 0x07,
+    GetStatus = 0x08,
+    /// Reboot, staying in `xspiloader` rather than booting an image
+    RebootToLoader = 0x09,
+    GetBuildInfo = 0x0a,
+    /// Device UUID and a MAC over a host nonce
+    Attest = 0x0b,
+    /// Sent by the device to the bench requester when a bench ends
+    BenchResult = 0x0c,
+    /// Log persisted from before the most recent reset
+    GetPreviousLog = 0x0d,
+    /// Requires `mctp-frag-test` feature
+    FragmentTest = 0x10,
+}
+
+impl CommandCode {
+    /// Returns whether the command is restricted to the vendor command
+    /// allowlist, if one is configured.
+    ///
+    /// These can generate traffic or disrupt the device. Queries are
+    /// open to any EID.
+    fn privileged(&self) -> bool {
+        matches!(
+            self,
+            Self::RequestBench
+                | Self::StopBench
+                | Self::SetLogLevel
+                | Self::Reboot
+                | Self::RebootToLoader
+        )
+    }
 }
 
 #[repr(u8)]
@@ -174,6 +870,11 @@ enum CommandResponse {
     Error = 0x01,
     UnknownCommand = 0x02,
     BadArgument = 0x03,
+    /// Code Construct extension, too many queued requests
+    Busy = 0x04,
+    /// Code Construct extension, the requester's EID is not in the vendor
+    /// command allowlist
+    NotPermitted = 0x05,
 }
 
 // Matches mctp-bench.c struct command_msg
@@ -196,6 +897,190 @@ struct CommandRequestBench {
     flags: u32,
     payload_size: u16,
     message_count: u64,
+    /// Code Construct extension, present with `FLAG_DELAY`
+    #[deku(cond = "*flags & MctpBench::FLAG_DELAY != 0", default = "0")]
+    delay_us: u32,
+}
+
+// Code Construct extension
+#[cfg(feature = "mctp-frag-test")]
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandFragmentTest {
+    /// MCTP packet size including header
+    packet_size: u16,
+    /// Length of the response payload following the status byte
+    payload_size: u16,
+}
+
+// Code Construct extension, StopBench response payload
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandStopBenchResponse {
+    /// Messages sent before stopping
+    sent: u64,
+}
+
+// Code Construct extension, SetLogLevel request and response payload.
+// A request may be followed by a module prefix such as "usbnvme::pldm",
+// setting the level for that module only. The module "@serial" sets the
+// USB serial log level instead.
+// Response has the previous level.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandSetLogLevel {
+    /// `log::LevelFilter`, 0 (Off) to 5 (Trace)
+    level: u8,
+}
+
+impl CommandSetLogLevel {
+    /// No module override. In a request, removes the override.
+    const LEVEL_NONE: u8 = 0xff;
+}
+
+// Code Construct extension, GetLog request payload
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandGetLog {
+    /// Sequence number of the first line to read
+    start: u32,
+}
+
+// Code Construct extension, GetLog response payload.
+// Followed by log text, whole lines terminated by '\n'.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandGetLogResponse {
+    /// Sequence number of the first line returned. Greater than the
+    /// requested `start` if lines were lost.
+    first: u32,
+    /// Sequence number to request next, equal to `first` if no lines
+    /// were returned.
+    next: u32,
+}
+
+impl CommandGetLogResponse {
+    const LEN: usize = 8;
+}
+
+// Code Construct extension, GetPreviousLog request payload
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandGetPreviousLog {
+    /// Byte offset to read from
+    offset: u32,
+}
+
+// Code Construct extension, GetPreviousLog response payload.
+// Followed by log text from the requested offset.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct CommandGetPreviousLogResponse {
+    /// Length of the whole previous log
+    total: u32,
+}
+
+impl CommandGetPreviousLogResponse {
+    const LEN: usize = 4;
+}
+
+// Code Construct extension, GetStatus response payload.
+// Followed by the product and version string.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct Status {
+    uptime_ms: u64,
+    uuid: [u8; 16],
+}
+
+// Code Construct extension, GetBuildInfo response payload.
+// Followed by the target triple string.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct BuildInfo {
+    version_major: u16,
+    version_minor: u16,
+    version_patch: u16,
+    /// Abbreviated git revision, ASCII hex
+    git_rev: [u8; 8],
+    /// 1 if built with uncommitted changes
+    dirty: u8,
+    /// 1 for a release build, 0 for debug
+    release: u8,
+}
+
+impl BuildInfo {
+    fn new() -> Self {
+        let num = |v: &str| v.parse().unwrap_or(u16::MAX);
+        Self {
+            version_major: num(env!("CARGO_PKG_VERSION_MAJOR")),
+            version_minor: num(env!("CARGO_PKG_VERSION_MINOR")),
+            version_patch: num(env!("CARGO_PKG_VERSION_PATCH")),
+            // build.rs checks the length
+            git_rev: env!("GIT_REV_SHORT").as_bytes().try_into().unwrap(),
+            dirty: (env!("GIT_DIRTY") == "1") as u8,
+            release: (env!("BUILD_PROFILE") == "release") as u8,
+        }
+    }
+}
+
+// Code Construct extension, Attest response payload.
+// The request body is the nonce.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct AttestResponse {
+    uuid: [u8; 16],
+    /// `attestation_mac()` of the nonce
+    mac: [u8; 32],
+}
+
+// Code Construct extension, BenchResult payload.
+// Sent in a new request message, with the RequestBench `iid`.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct BenchResult {
+    /// `BenchStatus`
+    status: u8,
+    /// Messages sent
+    messages: u64,
+    /// Message bytes sent, excluding the MCTP message type
+    bytes: u64,
+    elapsed_ms: u64,
+    /// Throughput in kB/s
+    kbps: u64,
+    /// Reply timeouts and mismatches in latency or echo mode
+    errors: u32,
+}
+
+impl BenchResult {
+    const LEN: usize = 37;
+}
+
+/// How a bench ended, in `BenchResult`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum BenchStatus {
+    Complete = 0,
+    /// Stopped by StopBench, a new request or USB down
+    Stopped = 1,
+    /// Sending failed
+    Failed = 2,
+}
+
+// Code Construct extension, GetDiagnostics response payload
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct Diagnostics {
+    /// `usb::UsbSpeed`
+    usb_speed: u8,
+    usb_configured: u8,
+    usb_resets: u32,
+    usb_suspends: u32,
+    usb_resumes: u32,
+    /// VDDA supply voltage in millivolts, 0 if unavailable
+    supply_mv: u16,
+    /// Incoming fragmented messages that never completed
+    reassembly_expired: u32,
 }
 
 /// Notification of a bench request
@@ -204,25 +1089,422 @@ pub struct BenchRequest {
     pub count: u64,
     pub len: usize,
     pub dest: Eid,
+    /// Instance ID of the RequestBench command, for `BenchResult`.
+    /// `None` for a bench started locally, which sends no result.
+    pub iid: Option<u32>,
+    /// Wait for a reply to each message, measuring round trip latency
+    pub latency: bool,
+    /// Send each message on a new tag, checking the echoed reply
+    pub echo: bool,
+    /// Delay after sending each message
+    pub delay: Option<Duration>,
+    pub pattern: BenchPattern,
+}
+
+/// Bench payload content, following the message header.
+///
+/// Patterns are generated from the start of the message, so a byte's
+/// value depends only on its offset.
+#[repr(u8)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
+pub enum BenchPattern {
+    /// Incrementing bytes, the `mctp-bench` default
+    Ramp = 0,
+    Zeros = 1,
+    Ones = 2,
+    /// PRBS-15 pseudo-random sequence
+    Prbs = 3,
+    /// Alternating 0x55 and 0xaa
+    Alternating = 4,
+}
+
+impl BenchPattern {
+    /// Patterns in the order tried by `detect()`
+    #[cfg(feature = "bench-verify")]
+    const ALL: [Self; 5] = [
+        Self::Ramp,
+        Self::Zeros,
+        Self::Ones,
+        Self::Prbs,
+        Self::Alternating,
+    ];
+
+    /// Returns the pattern bytes from offset 0.
+    fn bytes(self) -> impl Iterator<Item = u8> {
+        // x^15 + x^14 + 1, all ones seed
+        let mut lfsr = 0x7fffu16;
+        let mut prbs = move || {
+            let mut b = 0u8;
+            for _ in 0..8 {
+                let bit = ((lfsr >> 14) ^ (lfsr >> 13)) & 1;
+                lfsr = ((lfsr << 1) | bit) & 0x7fff;
+                b = (b << 1) | bit as u8;
+            }
+            b
+        };
+
+        (0..).map(move |i: usize| match self {
+            Self::Ramp => (i & 0xff) as u8,
+            Self::Zeros => 0x00,
+            Self::Ones => 0xff,
+            Self::Prbs => prbs(),
+            Self::Alternating => {
+                if i % 2 == 0 {
+                    0x55
+                } else {
+                    0xaa
+                }
+            }
+        })
+    }
+
+    /// Returns the offset and expected value of the first payload byte
+    /// of `msg` that doesn't match the pattern.
+    fn mismatch(self, msg: &[u8]) -> Option<(usize, u8)> {
+        msg.iter()
+            .zip(self.bytes())
+            .enumerate()
+            .skip(MctpBench::BENCH_HEADER_LEN)
+            .find(|(_, (b, p))| *b != p)
+            .map(|(i, (_, p))| (i, p))
+    }
+
+    /// Returns the pattern matching a message payload.
+    #[cfg(feature = "bench-verify")]
+    fn detect(msg: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.mismatch(msg).is_none())
+    }
+}
+
+/// Maximum number of concurrent benches, each to a different EID
+pub const BENCH_FANOUT: usize = 4;
+
+/// Communication between vendor commands and one bench sender.
+pub struct BenchSlot {
+    /// Pending bench request, cancelling any running bench
+    pub request: SignalCS<BenchRequest>,
+    /// Stops a running bench
+    pub stop: SignalCS<()>,
+    /// Messages sent by the stopped bench, in response to `stop`
+    pub stopped: SignalCS<u64>,
 }
 
+impl BenchSlot {
+    const fn new() -> Self {
+        Self {
+            request: Signal::new(),
+            stop: Signal::new(),
+            stopped: Signal::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SlotState {
+    /// Destination of the current or last bench
+    dest: Option<Eid>,
+    /// A bench is running or pending
+    busy: bool,
+}
+
+/// Communication between vendor commands and the bench task.
+///
+/// Benches to different destinations run concurrently, each in its own
+/// slot. A new request to a destination restarts only that bench.
+pub struct BenchControl {
+    state: BlockingMutex<
+        CriticalSectionRawMutex,
+        RefCell<[SlotState; BENCH_FANOUT]>,
+    >,
+    pub slots: [BenchSlot; BENCH_FANOUT],
+}
+
+impl BenchControl {
+    pub const fn new() -> Self {
+        const IDLE: SlotState = SlotState {
+            dest: None,
+            busy: false,
+        };
+        Self {
+            state: BlockingMutex::new(RefCell::new([IDLE; BENCH_FANOUT])),
+            slots: [const { BenchSlot::new() }; BENCH_FANOUT],
+        }
+    }
+
+    /// Starts a bench.
+    ///
+    /// Uses the slot of the current or last bench to the same
+    /// destination, otherwise any idle slot. Returns `false` if all
+    /// slots are busy with other destinations.
+    pub fn start(&self, req: BenchRequest) -> bool {
+        self.state.lock(|st| {
+            let mut st = st.borrow_mut();
+            let Some(i) = st
+                .iter()
+                .position(|s| s.dest == Some(req.dest))
+                .or_else(|| st.iter().position(|s| !s.busy))
+            else {
+                return false;
+            };
+            st[i] = SlotState {
+                dest: Some(req.dest),
+                busy: true,
+            };
+            self.slots[i].request.signal(req);
+            true
+        })
+    }
+
+    /// Returns the slot of the current or last bench to `dest`.
+    fn find(&self, dest: Eid) -> Option<&BenchSlot> {
+        self.state.lock(|st| {
+            let i = st.borrow().iter().position(|s| s.dest == Some(dest))?;
+            Some(&self.slots[i])
+        })
+    }
+
+    /// Stops all running benches.
+    pub fn stop_all(&self) {
+        self.state.lock(|st| {
+            for (s, slot) in st.borrow().iter().zip(&self.slots) {
+                if s.busy {
+                    slot.stop.signal(());
+                }
+            }
+        })
+    }
+
+    /// Marks the bench in slot `i` idle, unless another request is
+    /// pending.
+    pub fn finish(&self, i: usize) {
+        self.state.lock(|st| {
+            if !self.slots[i].request.signaled() {
+                st.borrow_mut()[i].busy = false;
+            }
+        })
+    }
+}
+
+/// Reply counts for an echo mode bench
+#[derive(Default, Debug)]
+struct EchoStats {
+    /// Replies identical to the sent message
+    matched: u64,
+    /// Replies with a different type, length or content
+    mismatched: u64,
+    /// Replies not received before `REPLY_TIMEOUT`
+    missing: u64,
+    reply_bytes: u64,
+}
+
+impl EchoStats {
+    fn log(&self) {
+        info!(
+            "mctp-bench echo replies: {} matched, {} mismatched, {} missing, {} bytes",
+            self.matched, self.mismatched, self.missing, self.reply_bytes
+        );
+    }
+}
+
+/// Histogram of bench round trip latency
+#[derive(Default, Debug)]
+struct LatencyHistogram {
+    /// Log spaced buckets, see `bound()`
+    buckets: [u64; Self::BUCKETS],
+    /// Replies that were not received before `REPLY_TIMEOUT`
+    timeouts: u64,
+}
+
+impl LatencyHistogram {
+    const BUCKETS: usize = 8;
+
+    /// Upper bound in microseconds of bucket `i`, 16us to 65ms.
+    ///
+    /// The last bucket is unbounded.
+    fn bound(i: usize) -> u64 {
+        16 << (2 * i)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros();
+        let i = (0..Self::BUCKETS - 1)
+            .find(|&i| us < Self::bound(i))
+            .unwrap_or(Self::BUCKETS - 1);
+        self.buckets[i] += 1;
+    }
+
+    fn log(&self) {
+        info!("mctp-bench round trip latency:");
+        for (i, n) in self.buckets.iter().enumerate() {
+            if i < Self::BUCKETS - 1 {
+                info!("  < {:6} us: {n}", Self::bound(i));
+            } else {
+                info!(" >= {:6} us: {n}", Self::bound(i - 1));
+            }
+        }
+        info!("  timeout    : {}", self.timeouts);
+    }
+}
+
+/// Receive side of `mctp-bench`.
+///
+/// Measures throughput of incoming bench messages, and counts
+/// missing sequence numbers.
+struct BenchSink {
+    /// Next expected sequence number. `None` until the first message.
+    next_seq: Option<Wrapping<u32>>,
+    /// Start of the current measurement interval
+    start: Instant,
+    interval_bytes: u64,
+    interval_msgs: u64,
+    total_msgs: u64,
+    dropped: u64,
+    /// Messages with an unexpected payload
+    #[cfg(feature = "bench-verify")]
+    corrupt: u64,
+    /// Expected payload, detected from the first message
+    #[cfg(feature = "bench-verify")]
+    pattern: Option<BenchPattern>,
+}
+
+impl BenchSink {
+    const LOG_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn new() -> Self {
+        Self {
+            next_seq: None,
+            start: Instant::now(),
+            interval_bytes: 0,
+            interval_msgs: 0,
+            total_msgs: 0,
+            dropped: 0,
+            #[cfg(feature = "bench-verify")]
+            corrupt: 0,
+            #[cfg(feature = "bench-verify")]
+            pattern: None,
+        }
+    }
+
+    /// Returns whether `msg` is a bench data message rather than a command
+    fn is_data(msg: &[u8]) -> bool {
+        msg.starts_with(&MctpBench::VENDOR_SUBTYPE)
+            && msg.get(3..5) == Some(&MctpBench::MAGIC.to_le_bytes()[..])
+    }
+
+    fn handle(&mut self, msg: &[u8]) {
+        let Some(seq) = msg.get(5..MctpBench::BENCH_HEADER_LEN) else {
+            trace!("Short bench message");
+            return;
+        };
+        let seq = Wrapping(u32::from_le_bytes(seq.try_into().unwrap()));
+
+        if seq.0 == MctpBench::SEQ_START {
+            if self.next_seq.is_some() {
+                self.log_summary();
+            }
+            info!("mctp-bench receive started");
+            *self = Self::new();
+        } else if let Some(next) = self.next_seq {
+            let gap = seq - next;
+            if gap.0 > u32::MAX / 2 {
+                // Sequence went backwards, don't count as a drop
+                debug!("Bench sequence {seq} out of order, expected {next}");
+            } else {
+                self.dropped += gap.0 as u64;
+            }
+        }
+        self.next_seq = Some(seq + Wrapping(1));
+
+        #[cfg(feature = "bench-verify")]
+        self.verify(msg, seq);
+
+        self.interval_bytes += msg.len() as u64;
+        self.interval_msgs += 1;
+        self.total_msgs += 1;
+
+        let elapsed = self.start.elapsed();
+        if elapsed >= Self::LOG_INTERVAL {
+            // bytes per ms is kB/s
+            let rate = self.interval_bytes / elapsed.as_millis();
+            info!(
+                "mctp-bench receive {} kB/s, {} msgs/s, {} total, {} dropped",
+                rate,
+                self.interval_msgs * 1000 / elapsed.as_millis(),
+                self.total_msgs,
+                self.dropped
+            );
+            self.start = Instant::now();
+            self.interval_bytes = 0;
+            self.interval_msgs = 0;
+        }
+    }
+
+    /// Checks that the payload after the header matches the sender's
+    /// pattern.
+    ///
+    /// The pattern is detected from the first message of a bench,
+    /// the ramp is assumed if none match.
+    #[cfg(feature = "bench-verify")]
+    fn verify(&mut self, msg: &[u8], seq: Wrapping<u32>) {
+        let pattern = *self.pattern.get_or_insert_with(|| {
+            let p = BenchPattern::detect(msg);
+            info!("mctp-bench receive pattern {p:?}");
+            p.unwrap_or(BenchPattern::Ramp)
+        });
+
+        if let Some((i, expect)) = pattern.mismatch(msg) {
+            self.corrupt += 1;
+            warn!(
+                "Bench sequence {seq} len {} mismatch at offset {i}, {:#04x} expected {expect:#04x}. {} corrupt",
+                msg.len(),
+                msg[i],
+                self.corrupt
+            );
+        }
+    }
+
+    fn log_summary(&self) {
+        info!(
+            "mctp-bench received {} messages, {} dropped",
+            self.total_msgs, self.dropped
+        );
+    }
+}
+
+/// Handles vendor messages from a `MCTP_TYPE_VENDOR_PCIE` listener.
 pub async fn listener(
-    router: &'static mctp_estack::Router<'static>,
-    bench_request: &SignalCS<BenchRequest>,
+    mut l: RouterAsyncListener<'static>,
+    routes: &Routes,
+    bench_control: &BenchControl,
+    logger: &MultiLog,
+    vendor_allow: Option<VendorAllowList>,
 ) -> ! {
     const VENDOR_SUBTYPE_ECHO: [u8; 3] = [0xcc, 0xde, 0xf0];
 
-    let mut l = router.listener(mctp::MCTP_TYPE_VENDOR_PCIE).unwrap();
-    let mut buf = [0u8; 100];
+    // Sized for bench messages
+    let mut buf = [0u8; mctp_estack::config::MAX_PAYLOAD];
+    let mut sink = BenchSink::new();
     loop {
         let Ok((_typ, _ic, msg, mut resp)) = l.recv(&mut buf).await else {
             warn!("echo Bad listener recv");
             continue;
         };
 
+        if BenchSink::is_data(msg) {
+            sink.handle(msg);
+            continue;
+        }
+
         if msg.starts_with(&MctpBench::VENDOR_SUBTYPE) {
-            let _ =
-                MctpBench::handle_request(msg, &mut resp, bench_request).await;
+            let _ = MctpBench::handle_request(
+                msg,
+                &mut resp,
+                routes,
+                bench_control,
+                logger,
+                vendor_allow.as_deref(),
+            )
+            .await;
             continue;
         }
 
@@ -239,3 +1521,66 @@ pub async fn listener(
         }
     }
 }
+
+/// PCI vendor ID prefixing Code Construct vendor messages
+const PCI_VENDOR_ID: u16 = 0xccde;
+
+/// Vendor command set types, following the PCI vendor ID.
+///
+/// Reported in this order by Get Vendor Defined Message Support.
+const VENDOR_COMMAND_SETS: [u16; 2] = [
+    0xf0, // mctp-echo
+    0xf1, // mctp-bench
+];
+
+/// MCTP Control Get Vendor Defined Message Support command code
+const CONTROL_GET_VENDOR_SUPPORT: u8 = 0x06;
+
+/// Response length, including the control message header
+pub const VENDOR_SUPPORT_RESP_LEN: usize = 9;
+
+/// Handles a MCTP Control Get Vendor Defined Message Support request.
+///
+/// `MctpControl` doesn't implement this command. Returns `None` for other
+/// messages, which should be passed to `MctpControl`.
+pub fn control_vendor_support<'a>(
+    msg: &[u8],
+    rsp: &'a mut [u8; VENDOR_SUPPORT_RESP_LEN],
+) -> Option<&'a [u8]> {
+    const RQ: u8 = 1 << 7;
+    const CC_SUCCESS: u8 = 0x00;
+    const CC_ERROR_INVALID_DATA: u8 = 0x02;
+    const CC_ERROR_INVALID_LENGTH: u8 = 0x03;
+    const VENDOR_ID_FORMAT_PCI: u8 = 0x00;
+    const NO_MORE_SETS: u8 = 0xff;
+
+    let [hdr, cmd, body @ ..] = msg else {
+        return None;
+    };
+    if hdr & RQ == 0 || *cmd != CONTROL_GET_VENDOR_SUPPORT {
+        return None;
+    }
+    rsp[0] = hdr & !RQ;
+    rsp[1] = *cmd;
+
+    let &[selector] = body else {
+        rsp[2] = CC_ERROR_INVALID_LENGTH;
+        return Some(&rsp[..3]);
+    };
+    let Some(set) = VENDOR_COMMAND_SETS.get(selector as usize) else {
+        rsp[2] = CC_ERROR_INVALID_DATA;
+        return Some(&rsp[..3]);
+    };
+
+    let next = selector as usize + 1;
+    rsp[2] = CC_SUCCESS;
+    rsp[3] = if next < VENDOR_COMMAND_SETS.len() {
+        next as u8
+    } else {
+        NO_MORE_SETS
+    };
+    rsp[4] = VENDOR_ID_FORMAT_PCI;
+    rsp[5..7].copy_from_slice(&PCI_VENDOR_ID.to_be_bytes());
+    rsp[7..9].copy_from_slice(&set.to_be_bytes());
+    Some(rsp)
+}
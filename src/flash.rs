@@ -0,0 +1,220 @@
+//! External XSPI flash access.
+//!
+//! Commands are from the `xspiflash` crate shared with `xspiloader`.
+//! This adds the usbnvme flash layout and erase or program waits that
+//! yield to the executor.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::ops::Range;
+
+use embassy_stm32::peripherals::{PN1, PN2, PN3, PN4, PN5, PN6, XSPI2};
+use embassy_stm32::xspi::Xspi;
+use embassy_stm32::Peri;
+use embassy_time::{Duration, Timer};
+use xspiflash::{FlashMemory, SECTOR_SIZE};
+
+use crate::bootctl::Slot;
+
+pub use xspiflash::Error as FlashError;
+
+/// Persistent settings.
+pub const SETTINGS_REGION: Range<u32> = 0x00ff_0000..0x00ff_1000;
+
+/// `xspiloader` boot slot selection.
+pub const BOOT_CONTROL_REGION: Range<u32> = 0x00ff_1000..0x00ff_2000;
+
+/// Provisioned records, such as the USB identity.
+///
+/// Kept apart from `SETTINGS_REGION`, so they aren't erased when settings
+/// are stored. Only written by provisioning, so it isn't writable here.
+pub const PROVISIONED_REGION: Range<u32> = 0x00ff_2000..0x00ff_3000;
+
+/// `xspiloader` image slots, as `Slot::region()` in `xspiloader`.
+///
+/// The slot that isn't running is the staging region for files received
+/// by PLDM file transfer.
+const SLOT_A_REGION: Range<u32> = 0x0000_0000..0x0080_0000;
+const SLOT_B_REGION: Range<u32> = 0x0080_0000..0x00ff_0000;
+
+/// Regions that may be erased or programmed, until a staging slot is set.
+///
+/// The running image slot must not be modified.
+const WRITABLE: &[Range<u32>] = &[SETTINGS_REGION, BOOT_CONTROL_REGION];
+/// Writable regions with slot A or B as staging.
+const WRITABLE_STAGING_A: &[Range<u32>] =
+    &[SETTINGS_REGION, BOOT_CONTROL_REGION, SLOT_A_REGION];
+const WRITABLE_STAGING_B: &[Range<u32>] =
+    &[SETTINGS_REGION, BOOT_CONTROL_REGION, SLOT_B_REGION];
+
+pub(crate) fn setup(
+    xspi: Peri<'static, XSPI2>,
+    clk: Peri<'static, PN6>,
+    d0: Peri<'static, PN2>,
+    d1: Peri<'static, PN3>,
+    d2: Peri<'static, PN4>,
+    d3: Peri<'static, PN5>,
+    ncs: Peri<'static, PN1>,
+) -> Result<Flash, FlashError> {
+    let xspi = Xspi::new_blocking_quadspi(
+        xspi,
+        clk,
+        d0,
+        d1,
+        d2,
+        d3,
+        ncs,
+        xspiflash::config(),
+    );
+    let mut mem = FlashMemory::new(xspi)?;
+    mem.set_writable(WRITABLE);
+    Ok(Flash { mem, staging: 0..0 })
+}
+
+pub struct Flash {
+    mem: FlashMemory<XSPI2>,
+    /// Empty until `set_staging()`
+    staging: Range<u32>,
+}
+
+impl Flash {
+    /// Makes the image slot `slot` the staging region, and writable.
+    ///
+    /// `slot` must not be the running image, and must not hold the
+    /// fallback for an unconfirmed running image.
+    pub fn set_staging(&mut self, slot: Slot) {
+        let (region, writable) = match slot {
+            Slot::A => (SLOT_A_REGION, WRITABLE_STAGING_A),
+            Slot::B => (SLOT_B_REGION, WRITABLE_STAGING_B),
+        };
+        info!("Staging to slot {slot:?} at {:#x}", region.start);
+        self.mem.set_writable(writable);
+        // Last sector holds the loader's image records
+        self.staging = region.start..region.end - SECTOR_SIZE as u32;
+    }
+
+    /// Files received by PLDM file transfer, the image slot that isn't
+    /// running, less its last sector.
+    ///
+    /// Empty if no staging slot is set.
+    pub fn staging_region(&self) -> Range<u32> {
+        self.staging.clone()
+    }
+
+    /// Erases the `xspiloader` image CRC and header records, in the
+    /// sector following the staging region.
+    ///
+    /// Records left from an earlier image would fail to match a newly
+    /// staged one.
+    pub async fn erase_staging_records(&mut self) -> Result<(), FlashError> {
+        if self.staging.is_empty() {
+            return Err(FlashError::Range);
+        }
+        self.sector_erase(self.staging.end).await
+    }
+
+    /// Waits for an erase or program to complete.
+    async fn wait_write_finish(&mut self) -> Result<(), FlashError> {
+        while self.mem.write_in_progress()? {
+            Timer::after(Duration::from_micros(100)).await;
+        }
+        Ok(())
+    }
+
+    pub fn read(
+        &mut self,
+        addr: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), FlashError> {
+        self.mem.read(addr, buffer)
+    }
+
+    /// Erases a `SECTOR_SIZE` sector.
+    ///
+    /// `addr` must be sector aligned.
+    pub async fn sector_erase(&mut self, addr: u32) -> Result<(), FlashError> {
+        self.mem.start_sector_erase(addr)?;
+        self.wait_write_finish().await
+    }
+
+    /// Programs data to previously erased flash.
+    ///
+    /// Writes are split at page boundaries.
+    pub async fn program(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), FlashError> {
+        // Nothing is written if any part is out of range
+        self.mem.check_writable(addr, data.len())?;
+        for (addr, chunk) in xspiflash::pages(addr, data) {
+            self.mem.start_page_program(addr, chunk)?;
+            self.wait_write_finish().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Sequential writer to a flash region.
+///
+/// Sectors are erased as they are first written. The writer only holds
+/// its position, so `Flash` need only be locked for each write.
+pub struct RegionWriter {
+    region: Range<u32>,
+    /// Next address to write
+    pos: u32,
+    /// End of the erased area
+    erased: u32,
+}
+
+impl RegionWriter {
+    /// Creates a writer continuing after `written` bytes of `region`,
+    /// written by an earlier `RegionWriter`.
+    ///
+    /// The rest of the sector at that point is assumed to be still erased.
+    /// `written` is 0 to start at the beginning of `region`.
+    pub fn resume(region: Range<u32>, written: usize) -> Self {
+        let pos = region.start + written as u32;
+        debug_assert!((region.start as usize).is_multiple_of(SECTOR_SIZE));
+        debug_assert!(pos <= region.end);
+        Self {
+            pos,
+            erased: pos.next_multiple_of(SECTOR_SIZE as u32),
+            region,
+        }
+    }
+
+    /// Appends data.
+    ///
+    /// Fails with `FlashError::Range` if the region is full.
+    pub async fn write(
+        &mut self,
+        flash: &mut Flash,
+        data: &[u8],
+    ) -> Result<(), FlashError> {
+        let end = self
+            .pos
+            .checked_add(data.len() as u32)
+            .filter(|e| *e <= self.region.end)
+            .ok_or(FlashError::Range)?;
+
+        while self.erased < end {
+            flash.sector_erase(self.erased).await?;
+            self.erased += SECTOR_SIZE as u32;
+        }
+
+        flash.program(self.pos, data).await?;
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Returns the number of bytes written.
+    pub fn written(&self) -> usize {
+        (self.pos - self.region.start) as usize
+    }
+}
@@ -0,0 +1,132 @@
+//! Panic message preserved across reset.
+//!
+//! The panic handler stores the message in a reserved RAM region
+//! that isn't cleared by reset or the bootloader. It is reported at
+//! the next boot. The watchdog also records an imminent reset here.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::cell::RefCell;
+use core::fmt::{Arguments, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use heapless::Vec;
+
+use crate::led::LED_STATUS;
+
+/// Maximum stored message length, truncated if longer.
+pub const MAX_MSG: usize = 200;
+
+const MAGIC: u32 = 0x434e_4150;
+
+#[repr(C)]
+struct PanicRecord {
+    magic: u32,
+    len: u32,
+    msg: [u8; MAX_MSG],
+}
+
+// Fits in PANIC_RAM in memory.x
+const _: () = assert!(size_of::<PanicRecord>() <= 240);
+
+#[link_section = ".panic_uninit"]
+static mut RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Message from before the most recent reset. Empty if none.
+static PREVIOUS: BlockingMutex<
+    CriticalSectionRawMutex,
+    RefCell<Vec<u8, MAX_MSG>>,
+> = BlockingMutex::new(RefCell::new(Vec::new()));
+
+/// Writes into a fixed buffer, truncating.
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let rest = &mut self.buf[self.len..];
+        let l = s.len().min(rest.len());
+        rest[..l].copy_from_slice(&s.as_bytes()[..l]);
+        self.len += l;
+        Ok(())
+    }
+}
+
+/// Stores a panic message.
+///
+/// Called from the panic handler. Doesn't allocate or take locks.
+pub fn save(info: &PanicInfo) {
+    save_message(format_args!("{info}"))
+}
+
+/// Stores a message to report after reset, such as an imminent
+/// watchdog reset.
+///
+/// Doesn't allocate or take locks.
+pub fn save_message(args: Arguments) {
+    // Safety: only accessed by the panic handler or a task immediately
+    // prior to reset, and `init()` prior to starting tasks.
+    let rec = unsafe { &mut *addr_of_mut!(RECORD).cast::<PanicRecord>() };
+
+    let mut w = Truncate {
+        buf: &mut rec.msg,
+        len: 0,
+    };
+    let _ = w.write_fmt(args);
+    rec.len = w.len as u32;
+    rec.magic = MAGIC;
+}
+
+/// Reports any panic message from before reset.
+///
+/// Must be called once at startup.
+pub fn init() {
+    // Safety: called before any panic handler could run concurrently.
+    // Contents may be uninitialised at power on, all values are
+    // valid for the types, and are validated by the magic.
+    let rec = unsafe { &mut *addr_of_mut!(RECORD).cast::<PanicRecord>() };
+
+    let magic = unsafe { addr_of_mut!(rec.magic).read_volatile() };
+    let len = rec.len as usize;
+    if magic != MAGIC || len > MAX_MSG {
+        info!("No prior panic");
+        return;
+    }
+    rec.magic = 0;
+    LED_STATUS.fault();
+
+    let msg = &rec.msg[..len];
+    info!(
+        "Before reset: {}",
+        core::str::from_utf8(msg).unwrap_or("(invalid)")
+    );
+    PREVIOUS.lock(|p| {
+        let mut p = p.borrow_mut();
+        p.clear();
+        // Length was checked above
+        let _ = p.extend_from_slice(msg);
+    });
+}
+
+/// Copies the message from before reset into `buf`.
+///
+/// Returns the length, 0 if there was no panic.
+pub fn previous(buf: &mut [u8]) -> usize {
+    PREVIOUS.lock(|p| {
+        let p = p.borrow();
+        let l = p.len().min(buf.len());
+        buf[..l].copy_from_slice(&p[..l]);
+        l
+    })
+}
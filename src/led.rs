@@ -0,0 +1,149 @@
+//! Status LED patterns.
+//!
+//! In order of precedence:
+//!
+//! - Triple flash for 30 seconds after a fault
+//! - Fast blink during a bench or PLDM file transfer
+//! - Solid when USB is up with an EID assigned
+//! - Short blink when USB is up with no EID
+//! - Slow blink when USB is down
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::gpio;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::SignalCS;
+
+pub static LED_STATUS: LedStatus = LedStatus::new();
+
+/// Time to show the fault pattern after a fault.
+const FAULT_SHOW: Duration = Duration::from_secs(30);
+
+/// LED on or off, and duration in milliseconds
+type Pattern = &'static [(bool, u64)];
+
+const SLOW: Pattern = &[(true, 1000), (false, 1000)];
+const SHORT: Pattern = &[(true, 100), (false, 1900)];
+const SOLID: Pattern = &[(true, 10_000)];
+const FAST: Pattern = &[(true, 100), (false, 100)];
+const FAULT: Pattern = &[
+    (true, 50),
+    (false, 150),
+    (true, 50),
+    (false, 150),
+    (true, 50),
+    (false, 1000),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Link {
+    UsbDown = 0,
+    NoEid = 1,
+    Ready = 2,
+}
+
+/// Device state shown by the LED.
+pub struct LedStatus {
+    /// `Link` as u8
+    link: AtomicU8,
+    /// Number of running transfers
+    busy: AtomicU8,
+    fault: AtomicBool,
+    changed: SignalCS<()>,
+}
+
+impl LedStatus {
+    const fn new() -> Self {
+        Self {
+            link: AtomicU8::new(Link::UsbDown as u8),
+            busy: AtomicU8::new(0),
+            fault: AtomicBool::new(false),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn set_link(&self, link: Link) {
+        self.link.store(link as u8, Ordering::Relaxed);
+        self.changed.signal(());
+    }
+
+    /// Shows a transfer as running until the returned guard is dropped.
+    pub fn busy(&self) -> BusyGuard<'_> {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        self.changed.signal(());
+        BusyGuard { status: self }
+    }
+
+    /// Shows the fault pattern for `FAULT_SHOW`.
+    pub fn fault(&self) {
+        self.fault.store(true, Ordering::Relaxed);
+        self.changed.signal(());
+    }
+
+    fn link(&self) -> Link {
+        match self.link.load(Ordering::Relaxed) {
+            2 => Link::Ready,
+            1 => Link::NoEid,
+            _ => Link::UsbDown,
+        }
+    }
+}
+
+pub struct BusyGuard<'a> {
+    status: &'a LedStatus,
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.status.busy.fetch_sub(1, Ordering::Relaxed);
+        self.status.changed.signal(());
+    }
+}
+
+#[embassy_executor::task]
+pub(crate) async fn blink_task(mut led: gpio::Output<'static>) {
+    let status = &LED_STATUS;
+    let mut fault_until = None;
+    loop {
+        let now = Instant::now();
+        if status.fault.swap(false, Ordering::Relaxed) {
+            fault_until = Some(now + FAULT_SHOW);
+        }
+        if fault_until.is_some_and(|t| now >= t) {
+            fault_until = None;
+        }
+
+        let pattern = if fault_until.is_some() {
+            FAULT
+        } else if status.busy.load(Ordering::Relaxed) != 0 {
+            FAST
+        } else {
+            match status.link() {
+                Link::UsbDown => SLOW,
+                Link::NoEid => SHORT,
+                Link::Ready => SOLID,
+            }
+        };
+
+        // Run one cycle of the pattern, restarting on a state change
+        for &(on, ms) in pattern {
+            led.set_level(on.into());
+            let delay = Timer::after(Duration::from_millis(ms));
+            if let Either::Second(()) =
+                select(delay, status.changed.wait()).await
+            {
+                break;
+            }
+        }
+    }
+}
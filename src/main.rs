@@ -11,53 +11,131 @@
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
 
+#[cfg(feature = "mctp-frag-test")]
+use core::cell::Cell;
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use heapless::Vec;
 use static_cell::StaticCell;
 
 use embassy_executor::{Executor, InterruptExecutor, Spawner};
-use embassy_futures::select::{select, Either};
+use embassy_futures::join::join_array;
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_stm32::interrupt;
 use embassy_stm32::interrupt::{InterruptExt, Priority};
+use embassy_stm32::time::Hertz;
 use embassy_stm32::{bind_interrupts, gpio, mode, peripherals, Config};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "mctp-frag-test")]
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
-use mctp::{AsyncListener, AsyncRespChannel};
+use mctp::{AsyncListener, AsyncReqChannel, AsyncRespChannel};
 use mctp::{Eid, MsgType};
 use mctp_estack::control::ControlEvent;
-use mctp_estack::router::{Port, PortId, PortLookup, PortTop, Router};
+use mctp_estack::router::{
+    Port, PortId, PortLookup, PortTop, Router, RouterAsyncListener,
+};
 
+mod bootctl;
 mod ccvendor;
+mod flash;
+mod lastpanic;
+mod led;
 mod multilog;
+#[cfg(feature = "nvme-mi")]
+mod nvmeconfig;
 #[cfg(feature = "pldm-file")]
 mod pldm;
+mod reassembly;
+mod routing;
+#[cfg(feature = "usb-selftest")]
+mod selftest;
+mod settings;
+#[cfg(feature = "usb-shell")]
+mod shell;
+#[cfg(feature = "nvme-mi-smbus")]
+mod smbus;
 mod stmutil;
 mod usb;
+mod watchdog;
 
-use ccvendor::BenchRequest;
+use ccvendor::BenchControl;
+use led::{Link, LED_STATUS};
 
 bind_interrupts!(struct Irqs {
     HASH => embassy_stm32::hash::InterruptHandler<peripherals::HASH>;
+    #[cfg(feature = "nvme-mi-smbus")]
+    I2C1_EV => embassy_stm32::i2c::EventInterruptHandler<peripherals::I2C1>;
+    #[cfg(feature = "nvme-mi-smbus")]
+    I2C1_ER => embassy_stm32::i2c::ErrorInterruptHandler<peripherals::I2C1>;
 });
 
-const USB_MTU: usize = 251;
+/// DSP0283 MCTP over USB header: DMTF ID, reserved, length
+const MCTP_USB_HEADER: usize = 4;
+/// Largest MCTP packet over USB, including the MCTP header.
+///
+/// The DSP0283 8-bit length field includes `MCTP_USB_HEADER`.
+const USB_MTU: usize = u8::MAX as usize - MCTP_USB_HEADER;
+const MCTP_HEADER: usize = 4;
+// Minimum MCTP transmission unit
+const MCTP_BASELINE_MTU: usize = 64;
+
+/// EID used when none is stored in flash, `Eid(0)` for none.
+///
+/// Set by the `USBNVME_DEFAULT_EID` build environment variable, for lab
+/// setups with fixed EIDs.
+const DEFAULT_EID: Eid = match option_env!("USBNVME_DEFAULT_EID") {
+    // build.rs writes a decimal value
+    Some(e) => match u8::from_str_radix(e, 10) {
+        Ok(e) => Eid(e),
+        Err(_) => panic!("Bad USBNVME_DEFAULT_EID"),
+    },
+    None => Eid(0),
+};
+// 0 to 7 are reserved, 0xff is broadcast
+const _: () = assert!(
+    DEFAULT_EID.0 == 0 || (DEFAULT_EID.0 >= 8 && DEFAULT_EID.0 != 0xff),
+    "USBNVME_DEFAULT_EID must be a valid EID"
+);
+
+/// Time after USB up before warning that no EID has been assigned.
+const EID_GRACE: Duration = Duration::from_secs(10);
+
+/// Interval between MCTP Discovery Notify messages, until an EID is assigned.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(2);
+/// Discovery Notify messages sent after each USB up.
+const DISCOVERY_ATTEMPTS: usize = 3;
+
+/// Time allowed to send a mctp-bench result summary.
+const BENCH_RESULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Returns a bench message length that exactly fills `n` packets.
+///
+/// Each packet carries `mtu` less the `MCTP_HEADER`. The message length
+/// excludes the one byte MCTP message type, sent in the first packet.
+const fn optimal_bench_len(mtu: usize, n: usize) -> usize {
+    n * (mtu - MCTP_HEADER) - 1
+}
 
-// Optimal BENCH_LEN is (N*247 - 1).
-// USB_MTU - 4, and one byte for MCTP message type.
 // Even N are more efficient.
-const BENCH_LEN: usize = 3951;
-// const BENCH_LEN: usize = 987;
-// const BENCH_LEN: usize = 246;
+const BENCH_LEN: usize = optimal_bench_len(USB_MTU, 16);
+// const BENCH_LEN: usize = optimal_bench_len(USB_MTU, 4);
+// const BENCH_LEN: usize = optimal_bench_len(USB_MTU, 1);
 const _: () = assert!(BENCH_LEN >= 9);
+// Fails if USB_MTU changes without BENCH_LEN following
+const _: () = assert!((BENCH_LEN + 1) % (USB_MTU - MCTP_HEADER) == 0);
 
 // Simple panic handler
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     multilog::enter_panic();
+    lastpanic::save(info);
+    multilog::persist_panic(info);
     error!("panicked. {}", info);
     loop {}
 }
@@ -67,6 +145,8 @@ type SharedHash = Mutex<
     embassy_stm32::hash::Hash<'static, peripherals::HASH, mode::Blocking>,
 >;
 
+type SharedFlash = Mutex<CriticalSectionRawMutex, flash::Flash>;
+
 static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
 static EXECUTOR_MEDIUM: InterruptExecutor = InterruptExecutor::new();
 static EXECUTOR_LOW: StaticCell<Executor> = StaticCell::new();
@@ -122,33 +202,158 @@ fn config() -> Config {
     config.rcc.voltage_scale = VoltageScale::HIGH;
 
     config.rcc.mux.usbphycsel = mux::Usbphycsel::PLL3_Q;
+    // 32MHz for the ADC
+    config.rcc.mux.adcsel = mux::Adcsel::PLL3_R;
     // i3c1 uses default p1 = 150MHz. Good multiple of 12.5Mhz SCL clock.
 
+    // USB silently fails to enumerate with a PHY clock out of range
+    match usb_phy_clock(&config) {
+        Some(f) if f <= USB_PHY_MAX => (),
+        Some(f) => panic!("USB PHY clock {f:?} exceeds {USB_PHY_MAX:?}"),
+        None => panic!("USB PHY clock is not PLL3 Q from HSI"),
+    }
+
     config
 }
 
+/// HSI frequency with `HSIPrescaler::DIV1`
+const HSI_FREQ: Hertz = Hertz::mhz(64);
+/// Maximum `usbphycsel` kernel clock
+const USB_PHY_MAX: Hertz = Hertz::mhz(32);
+
+/// Returns the USB PHY clock from PLL3 Q, derived from the configured
+/// dividers.
+///
+/// The dividers' frequency arithmetic isn't `const`, so this is checked
+/// at startup rather than build time. Returns `None` if PLL3 Q isn't
+/// enabled from the HSI.
+fn usb_phy_clock(config: &Config) -> Option<Hertz> {
+    use embassy_stm32::rcc::*;
+    let pll3 = config.rcc.pll3.as_ref()?;
+    if pll3.source != PllSource::HSI
+        || config.rcc.hsi != Some(HSIPrescaler::DIV1)
+        || config.rcc.mux.usbphycsel != mux::Usbphycsel::PLL3_Q
+    {
+        return None;
+    }
+    Some(HSI_FREQ / pll3.prediv * pll3.mul / pll3.divq?)
+}
+
 pub fn now() -> u64 {
     Instant::now().as_millis()
 }
 
-struct Routes {}
+/// Current time in microseconds.
+///
+/// Resolution is limited by the 32768Hz embassy-time tick, about 30us.
+pub fn now_us() -> u64 {
+    Instant::now().as_micros()
+}
+
+struct Routes {
+    /// Current MTU for the USB port.
+    ///
+    /// May be lowered at runtime by a NVMe-MI Set MTU.
+    usb_mtu: AtomicUsize,
+
+    /// Forced fragment size for conformance testing, and the only
+    /// destination it applies to.
+    #[cfg(feature = "mctp-frag-test")]
+    frag_override:
+        BlockingMutex<CriticalSectionRawMutex, Cell<Option<(Eid, usize)>>>,
+}
 
 impl Routes {
     const USB_INDEX: PortId = PortId(0);
+    #[cfg(feature = "nvme-mi-smbus")]
+    const SMBUS_INDEX: PortId = PortId(1);
+
+    const fn new() -> Self {
+        Self {
+            usb_mtu: AtomicUsize::new(USB_MTU),
+            #[cfg(feature = "mctp-frag-test")]
+            frag_override: BlockingMutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Sets the USB port MTU.
+    ///
+    /// Returns `Err` if outside the baseline MTU and `USB_MTU`, leaving
+    /// the MTU unchanged, so that callers can report a rejected value.
+    fn set_usb_mtu(&self, mtu: usize) -> Result<(), ()> {
+        if !(MCTP_BASELINE_MTU + MCTP_HEADER..=USB_MTU).contains(&mtu) {
+            return Err(());
+        }
+        self.usb_mtu.store(mtu, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Forces the packet size used for fragmenting sends to `dest` over
+    /// USB.
+    ///
+    /// This applies until `clear_fragment_override()`, regardless of the
+    /// USB port MTU. Other destinations are unaffected.
+    /// The size is clamped between the baseline MTU and `USB_MTU`.
+    ///
+    /// Returns `false` if an override is already set, leaving it
+    /// unchanged.
+    #[cfg(feature = "mctp-frag-test")]
+    fn set_fragment_override(&self, dest: Eid, mtu: usize) -> bool {
+        let mtu = mtu.clamp(MCTP_BASELINE_MTU + MCTP_HEADER, USB_MTU);
+        self.frag_override.lock(|o| {
+            if o.get().is_some() {
+                return false;
+            }
+            o.set(Some((dest, mtu)));
+            true
+        })
+    }
+
+    #[cfg(feature = "mctp-frag-test")]
+    fn clear_fragment_override(&self) {
+        self.frag_override.lock(|o| o.set(None))
+    }
+
+    /// Current USB port MTU for outgoing packets, including the MCTP
+    /// header.
+    fn mtu(&self) -> usize {
+        self.usb_mtu.load(Ordering::Relaxed)
+    }
+
+    /// MTU for packets to `eid` over USB, with any fragment test
+    /// override.
+    ///
+    /// This is the single source used by the router for fragmenting.
+    fn usb_mtu_for(
+        &self,
+        #[cfg_attr(not(feature = "mctp-frag-test"), allow(unused))] eid: Eid,
+    ) -> usize {
+        #[cfg(feature = "mctp-frag-test")]
+        if let Some((dest, mtu)) = self.frag_override.lock(|o| o.get()) {
+            if dest == eid {
+                return mtu;
+            }
+        }
+        self.mtu()
+    }
 }
 
 impl PortLookup for Routes {
     fn by_eid(
         &self,
-        _eid: Eid,
+        eid: Eid,
         src_port: Option<PortId>,
     ) -> (Option<PortId>, Option<usize>) {
-        if src_port == Some(Self::USB_INDEX) {
-            // Avoid routing loops
+        if src_port.is_some() {
+            // Not a bridge, and avoid routing loops
             return (None, None);
         }
-        // All packets out USB
-        (Some(Self::USB_INDEX), Some(USB_MTU))
+        #[cfg(feature = "nvme-mi-smbus")]
+        if smbus::NEIGHBOURS.addr(eid).is_some() {
+            return (Some(Self::SMBUS_INDEX), Some(smbus::SMBUS_MTU));
+        }
+        // Other packets out USB
+        (Some(Self::USB_INDEX), Some(self.usb_mtu_for(eid)))
     }
 }
 
@@ -166,6 +371,25 @@ pub fn device_uuid() -> uuid::Uuid {
     uuid::Builder::from_random_bytes(u).into_uuid()
 }
 
+/// Attestation MAC over a host nonce.
+///
+/// HMAC-SHA256 of the device UUID followed by `nonce`, keyed by
+/// HMAC-SHA256 of the label `attestkey` keyed by the hardware device ID.
+/// A provisioning server records the key for each device at manufacture.
+pub fn attestation_mac(nonce: &[u8]) -> [u8; 32] {
+    use hmac::Mac;
+    let devid = stmutil::device_id();
+    let mut k = hmac::Hmac::<sha2::Sha256>::new_from_slice(&devid).unwrap();
+    // Distinct from the device_uuid() label
+    k.update(b"attestkey");
+    let k = k.finalize().into_bytes();
+
+    let mut m = hmac::Hmac::<sha2::Sha256>::new_from_slice(&k).unwrap();
+    m.update(device_uuid().as_bytes());
+    m.update(nonce);
+    m.finalize().into_bytes().into()
+}
+
 pub const PRODUCT: &str = concat!(
     "usbnvme",
     "-",
@@ -178,15 +402,33 @@ pub const PRODUCT: &str = concat!(
 fn main() -> ! {
     let logger = multilog::init();
     info!("{}. device {}", PRODUCT, device_uuid().hyphenated());
+    info!("Reset cause {:?}", stmutil::reset_cause());
+    let boot = stmutil::boot_mailbox();
+    match &boot {
+        Some(b) => info!("Boot reason {:?}, slot {}", b.reason, b.slot),
+        None => info!("No boot reason from loader"),
+    }
+    lastpanic::init();
     debug!("debug log enabled");
     trace!("trace log enabled");
 
     let executor = EXECUTOR_LOW.init(Executor::new());
-    executor.run(|spawner| run(spawner, logger))
+    executor.run(|spawner| run(spawner, logger, boot))
 }
 
-fn setup_mctp() -> (&'static Router<'static>, Port<'static>) {
+/// Router ports, from `setup_mctp()`
+struct MctpPorts {
+    usb: Port<'static>,
+    #[cfg(feature = "nvme-mi-smbus")]
+    smbus: Port<'static>,
+}
+
+fn setup_mctp(
+    own_eid: Eid,
+) -> (&'static Router<'static>, &'static Routes, MctpPorts) {
     static USB_TOP: StaticCell<PortTop> = StaticCell::new();
+    #[cfg(feature = "nvme-mi-smbus")]
+    static SMBUS_TOP: StaticCell<PortTop> = StaticCell::new();
     static LOOKUP: StaticCell<Routes> = StaticCell::new();
     static ROUTER: StaticCell<Router> = StaticCell::new();
 
@@ -194,19 +436,83 @@ fn setup_mctp() -> (&'static Router<'static>, Port<'static>) {
     let usb_top = USB_TOP.init_with(PortTop::new);
 
     // MCTP stack
-    let lookup = LOOKUP.init(Routes {});
+    let lookup: &'static Routes = LOOKUP.init(Routes::new());
     // Router is large, using init_with() is important to construct in-place
-    let router = ROUTER.init_with(|| Router::new(Eid(0), lookup, now()));
+    let router = ROUTER.init_with(|| Router::new(own_eid, lookup, now()));
     let usb_id = router.add_port(usb_top).unwrap();
     debug_assert_eq!(usb_id, Routes::USB_INDEX);
     let usb_port = router.port(Routes::USB_INDEX).unwrap();
 
-    (router, usb_port)
+    // SMBus port, after USB
+    #[cfg(feature = "nvme-mi-smbus")]
+    let smbus_port = {
+        let smbus_top = SMBUS_TOP.init_with(PortTop::new);
+        let smbus_id = router.add_port(smbus_top).unwrap();
+        debug_assert_eq!(smbus_id, Routes::SMBUS_INDEX);
+        router.port(Routes::SMBUS_INDEX).unwrap()
+    };
+
+    let ports = MctpPorts {
+        usb: usb_port,
+        #[cfg(feature = "nvme-mi-smbus")]
+        smbus: smbus_port,
+    };
+    (router, lookup, ports)
 }
 
 type SignalCS<T> = embassy_sync::signal::Signal<CriticalSectionRawMutex, T>;
 
-fn run(low_spawner: Spawner, logger: &'static multilog::MultiLog) {
+/// Message types advertised, limited by `MctpControl`
+const MAX_MSG_TYPES: usize = 8;
+
+/// Listeners for the handled MCTP message types.
+///
+/// Listeners are only created here, so the types advertised by Get
+/// Message Type Support are those with a listener.
+struct MsgTypes {
+    router: &'static Router<'static>,
+    types: Vec<MsgType, MAX_MSG_TYPES>,
+}
+
+impl MsgTypes {
+    fn new(router: &'static Router<'static>) -> Self {
+        Self {
+            router,
+            types: Vec::new(),
+        }
+    }
+
+    /// Creates the listener for `typ`, and advertises the type.
+    fn listen(&mut self, typ: MsgType) -> RouterAsyncListener<'static> {
+        let l = self.router.listener(typ).expect("MCTP listener");
+        self.types.push(typ).expect("Too many MCTP message types");
+        l
+    }
+
+    fn contains(&self, typ: MsgType) -> bool {
+        self.types.contains(&typ)
+    }
+}
+
+/// Message types echoed by `echo_any_task`, if not otherwise handled.
+#[cfg(feature = "mctp-echo-any")]
+const ECHO_TYPES: &[MsgType] = &[
+    mctp::MCTP_TYPE_NVME,
+    mctp::MCTP_TYPE_SPDM,
+    mctp::MCTP_TYPE_PCIE_MI,
+    mctp::MCTP_TYPE_VENDOR_IANA,
+];
+
+/// Queued control protocol events, so that none are lost.
+type ControlChannel = Channel<CriticalSectionRawMutex, ControlEvent, 4>;
+
+/// `boot` is the image slot and boot reason reported by `xspiloader`, if
+/// any.
+fn run(
+    low_spawner: Spawner,
+    logger: &'static multilog::MultiLog,
+    boot: Option<stmutil::BootMailbox>,
+) {
     // Highest priority goes to the USB send task, to fill the TX buffer
     // as quickly as possible once it becomes ready.
     //
@@ -226,25 +532,75 @@ fn run(low_spawner: Spawner, logger: &'static multilog::MultiLog) {
 
     let led = gpio::Output::new(p.PD13, gpio::Level::High, gpio::Speed::Low);
 
+    let wdg = watchdog::setup(p.IWDG);
+    stmutil::init_sensors(p.ADC2);
+
+    #[cfg(feature = "usb-selftest")]
+    if !selftest::run() {
+        led::LED_STATUS.fault();
+    }
+
     static HASH: StaticCell<SharedHash> = StaticCell::new();
     let hash = HASH.init(Mutex::new(embassy_stm32::hash::Hash::new_blocking(
         p.HASH, Irqs,
     )));
     let _ = hash;
 
+    // Without flash, defaults are used and flash-backed features are
+    // disabled, as for a board without external flash.
+    let mut flash =
+        flash::setup(p.XSPI2, p.PN6, p.PN2, p.PN3, p.PN4, p.PN5, p.PN1)
+            .inspect_err(|e| error!("XSPI flash unavailable: {e:?}"))
+            .ok();
+    // Staging slot, once the running slot is confirmed
+    let mut deferred_staging = None;
+    let settings = match flash.as_mut() {
+        Some(flash) => {
+            let staging =
+                bootctl::staging_slot(flash, boot.as_ref().map(|b| b.slot));
+            if bootctl::running_trial(flash, boot.as_ref()) {
+                // The staging slot holds the fallback image until then
+                info!("Staging deferred until the boot slot is confirmed");
+                deferred_staging = Some(staging);
+            } else {
+                flash.set_staging(staging);
+            }
+            settings::Settings::load(flash)
+        }
+        None => settings::Settings::default(),
+    };
+    info!("Settings {settings:?}");
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash = flash.map(|f| &*FLASH.init(Mutex::new(f)));
+
     /// Notification of the remote peer.
     ///
     /// Set on each Set Endpoint ID call. Initially None.
     static PEER_NOTIFY: SignalCS<Eid> = Signal::new();
-    static USB_NOTIFY: SignalCS<bool> = Signal::new();
-    static CONTROL_NOTIFY: SignalCS<ControlEvent> = Signal::new();
-    static BENCH_REQUEST: SignalCS<BenchRequest> = Signal::new();
-
-    let (router, mctp_usb_bottom) = setup_mctp();
+    static USB_NOTIFY: SignalCS<usb::UsbState> = Signal::new();
+    static CONTROL_NOTIFY: ControlChannel = Channel::new();
+    static BENCH_CONTROL: BenchControl = BenchControl::new();
+    static SETTINGS_EID: SignalCS<Eid> = Signal::new();
+    static DISCOVERY: SignalCS<bool> = Signal::new();
+    static LINK_DOWN: SignalCS<()> = Signal::new();
+    static BOOT_WORKING: SignalCS<()> = Signal::new();
+
+    let initial_eid = if settings.eid != Eid(0) {
+        settings.eid
+    } else {
+        DEFAULT_EID
+    };
+    let (router, routes, ports) = setup_mctp(initial_eid);
 
     // MCTP over USB class device
-    let endpoints =
-        usb::setup(low_spawner, p.USB_OTG_HS, p.PM6, p.PM5, &USB_NOTIFY);
+    let endpoints = usb::setup(
+        low_spawner,
+        p.USB_OTG_HS,
+        p.PM6,
+        p.PM5,
+        &USB_NOTIFY,
+        settings.usb.clone(),
+    );
 
     #[cfg(feature = "log-usbserial")]
     let (mctpusb, usbserial) = endpoints;
@@ -253,127 +609,392 @@ fn run(low_spawner: Spawner, logger: &'static multilog::MultiLog) {
 
     let (usb_sender, usb_receiver) = mctpusb.split();
 
-    let echo = echo_task(router, &BENCH_REQUEST).unwrap();
+    let mut msg_types = MsgTypes::new(router);
+    let control_listener = msg_types.listen(mctp::MCTP_TYPE_CONTROL);
+    let echo = echo_task(
+        msg_types.listen(mctp::MCTP_TYPE_VENDOR_PCIE),
+        routes,
+        &BENCH_CONTROL,
+        logger,
+        settings.vendor_allow.clone(),
+    )
+    .unwrap();
+    #[cfg(feature = "nvme-mi")]
+    let nvme_listener = msg_types.listen(mctp::MCTP_TYPE_NVME);
+    // After the real handlers, so that their types are skipped
+    #[cfg(feature = "mctp-echo-any")]
+    for &typ in ECHO_TYPES {
+        if typ == mctp::MCTP_TYPE_CONTROL || msg_types.contains(typ) {
+            debug!("Not echoing handled type {typ}");
+            continue;
+        }
+        medium_spawner.spawn(echo_any_task(msg_types.listen(typ)).unwrap());
+    }
+
     let timeout = timeout_task(router).unwrap();
-    let control = control_task(router, &CONTROL_NOTIFY).unwrap();
-    let usb_send_loop =
-        usb::usb_send_task(mctp_usb_bottom, usb_sender).unwrap();
+    let control = control_task(
+        router,
+        control_listener,
+        msg_types.types,
+        &CONTROL_NOTIFY,
+    )
+    .unwrap();
+    let discovery = discovery_task(router, &DISCOVERY).unwrap();
+    let usb_send_loop = usb::usb_send_task(ports.usb, usb_sender).unwrap();
     let usb_recv_loop =
         usb::usb_recv_task(router, usb_receiver, Routes::USB_INDEX).unwrap();
-    let app_loop =
-        usbnvme_app_task(&USB_NOTIFY, &CONTROL_NOTIFY, &PEER_NOTIFY).unwrap();
-
-    low_spawner.spawn(blink_task(led).unwrap());
+    let app_loop = usbnvme_app_task(
+        &USB_NOTIFY,
+        &CONTROL_NOTIFY,
+        &PEER_NOTIFY,
+        &SETTINGS_EID,
+        &DISCOVERY,
+        &BENCH_CONTROL,
+        &LINK_DOWN,
+        &BOOT_WORKING,
+        initial_eid,
+    )
+    .unwrap();
+    #[cfg(feature = "nvme-mi")]
+    let nvme = nvmeconfig::NvmeConfig::select(&settings);
+
+    low_spawner.spawn(led::blink_task(led).unwrap());
+    low_spawner.spawn(watchdog::watchdog_task(wdg).unwrap());
+    if let Some(flash) = flash {
+        let settings_loop =
+            settings::settings_task(flash, settings, &SETTINGS_EID).unwrap();
+        low_spawner.spawn(settings_loop);
+        let confirm =
+            bootctl::confirm_task(flash, &BOOT_WORKING, deferred_staging)
+                .unwrap();
+        low_spawner.spawn(confirm);
+    }
     medium_spawner.spawn(echo);
     medium_spawner.spawn(timeout);
     medium_spawner.spawn(usb_recv_loop);
     medium_spawner.spawn(control);
+    medium_spawner.spawn(discovery);
     medium_spawner.spawn(app_loop);
     // high priority for usb send
     high_spawner.spawn(usb_send_loop);
 
     #[cfg(feature = "nvme-mi")]
     {
-        let nvmemi = nvme_mi_task(router).unwrap();
+        let nvmemi = nvme_mi_task(nvme_listener, routes, nvme).unwrap();
         medium_spawner.spawn(nvmemi);
+        low_spawner.spawn(stmutil::sensor_task().unwrap());
     }
-    #[cfg(feature = "pldm-file")]
+    #[cfg(feature = "nvme-mi-smbus")]
     {
+        let i2c =
+            smbus::setup(p.I2C1, p.PB8, p.PB9, p.GPDMA1_CH4, p.GPDMA1_CH5);
+        let smbus_loop =
+            smbus::smbus_task(router, i2c, ports.smbus, Routes::SMBUS_INDEX)
+                .unwrap();
+        medium_spawner.spawn(smbus_loop);
+    }
+    #[cfg(feature = "pldm-file")]
+    if let Some(flash) = flash {
         let pldm_file =
-            pldm::pldm_file_task(router, &PEER_NOTIFY, hash).unwrap();
+            pldm::pldm_file_task(router, &PEER_NOTIFY, &LINK_DOWN, hash, flash)
+                .unwrap();
         medium_spawner.spawn(pldm_file);
     }
     #[cfg(feature = "mctp-bench")]
     {
-        let bench = bench_task(router, &BENCH_REQUEST).unwrap();
+        let bench = bench_task(router, &BENCH_CONTROL).unwrap();
         low_spawner.spawn(bench);
     }
-    let _ = logger;
     #[cfg(feature = "log-usbserial")]
     {
-        let (sender, _) = usbserial.split();
+        let (sender, _receiver) = usbserial.split();
         let seriallog = multilog::log_usbserial_task(sender, logger).unwrap();
         low_spawner.spawn(seriallog);
+        #[cfg(feature = "usb-shell")]
+        {
+            let shell = shell::shell_task(
+                _receiver,
+                router,
+                routes,
+                &BENCH_CONTROL,
+                logger,
+            )
+            .unwrap();
+            low_spawner.spawn(shell);
+        }
     }
 }
 
 /// Task to handle usbnvme state transitions.
+///
+/// A warning is logged if no EID has been assigned within `EID_GRACE`
+/// of USB coming up.
+///
+/// USB down cancels outstanding bench and PLDM requests.
+///
+/// The booted image slot is confirmed by `boot_working` on the first Set
+/// Endpoint ID with USB up.
+///
+/// USB enumerated at full speed is treated as down, since the 512 byte
+/// MCTP endpoints are invalid at full speed.
 #[allow(unused)]
 #[embassy_executor::task]
 async fn usbnvme_app_task(
-    usb_state_notify: &'static SignalCS<bool>,
-    control_notify: &'static SignalCS<ControlEvent>,
+    usb_state_notify: &'static SignalCS<usb::UsbState>,
+    control_notify: &'static ControlChannel,
     peer_watch: &'static SignalCS<Eid>,
+    settings_eid: &'static SignalCS<Eid>,
+    discovery: &'static SignalCS<bool>,
+    bench_control: &'static BenchControl,
+    link_down: &'static SignalCS<()>,
+    boot_working: &'static SignalCS<()>,
+    initial_eid: Eid,
 ) -> ! {
     let mut usb_state = false;
+    let mut eid_assigned = initial_eid != Eid(0);
+    // Deadline for EID assignment, when waiting
+    let mut grace_end = None;
     loop {
+        let grace = async {
+            match grace_end {
+                Some(t) => Timer::at(t).await,
+                None => core::future::pending().await,
+            }
+        };
+
         // Wait for either
         // - usb up/down event
         // - Set Endpoint ID from a bus owner.
-        match select(usb_state_notify.wait(), control_notify.wait()).await {
-            Either::First(s) => {
+        // - EID assignment grace period expiry
+        match select3(usb_state_notify.wait(), control_notify.receive(), grace)
+            .await
+        {
+            Either3::First(s) => {
                 info!("USB state -> {s:?}");
-                usb_state = s;
+                usb_state = match s {
+                    usb::UsbState::Up(usb::UsbSpeed::High) => true,
+                    usb::UsbState::Up(speed) => {
+                        error!("Not using MCTP over USB at {speed:?} speed");
+                        LED_STATUS.fault();
+                        false
+                    }
+                    usb::UsbState::Down => false,
+                };
+                grace_end = (usb_state && !eid_assigned)
+                    .then(|| Instant::now() + EID_GRACE);
+                // A stored EID may be unknown to this bus owner, so
+                // notify on each USB up.
+                discovery.signal(usb_state);
+                if !usb_state {
+                    // Outstanding requests can't complete. Cancelling them
+                    // releases their tags, so a response sent before the
+                    // disconnect can't be delivered to a later request.
+                    bench_control.stop_all();
+                    link_down.signal(());
+                }
             }
-            Either::Second(ev) => match ev {
-                // TODO: if more event variants are added, we may need to replace Signal
-                // with a >1 sized Channel to ensure we don't lose events.
+            Either3::Second(ev) => match ev {
                 ControlEvent::SetEndpointId {
                     old,
                     new,
                     bus_owner,
                 } => {
                     info!("Own EID changed {old} -> {new} by bus owner {bus_owner}");
+                    eid_assigned = true;
+                    grace_end = None;
+                    discovery.signal(false);
                     peer_watch.signal(bus_owner);
+                    settings_eid.signal(new);
+                    if usb_state {
+                        boot_working.signal(());
+                    }
                 }
             },
+            Either3::Third(()) => {
+                warn!("No EID assigned {}s after USB up", EID_GRACE.as_secs());
+                grace_end = None;
+            }
         }
+
+        LED_STATUS.set_link(match (usb_state, eid_assigned) {
+            (false, _) => Link::UsbDown,
+            (true, false) => Link::NoEid,
+            (true, true) => Link::Ready,
+        });
     }
 }
 
 #[allow(unused)]
 #[embassy_executor::task]
 async fn echo_task(
-    router: &'static mctp_estack::Router<'static>,
-    bench_request: &'static SignalCS<BenchRequest>,
+    listener: RouterAsyncListener<'static>,
+    routes: &'static Routes,
+    bench_control: &'static BenchControl,
+    logger: &'static multilog::MultiLog,
+    vendor_allow: Option<settings::VendorAllowList>,
 ) -> ! {
-    ccvendor::listener(router, bench_request).await
+    ccvendor::listener(listener, routes, bench_control, logger, vendor_allow)
+        .await
+}
+
+/// Replies to any message with the same payload, message type and
+/// integrity check bit.
+#[cfg(feature = "mctp-echo-any")]
+#[embassy_executor::task(pool_size = ECHO_TYPES.len())]
+async fn echo_any_task(mut l: RouterAsyncListener<'static>) -> ! {
+    let mut buf = [0u8; mctp_estack::config::MAX_PAYLOAD];
+    loop {
+        let (typ, ic, msg, mut resp) = match l.recv(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("echo recv err: {e}");
+                continue;
+            }
+        };
+        debug!(
+            "echo type {typ} len {} from eid {}",
+            msg.len(),
+            resp.remote_eid()
+        );
+        if let Err(e) = resp.send_vectored(ic, &[msg]).await {
+            warn!("echo reply failed: {e}");
+        }
+    }
 }
 
 /// Checks timeouts in the MCTP stack.
+///
+/// Incomplete messages expired by the stack are also reported by
+/// `reassembly::REASSEMBLY`.
 #[embassy_executor::task]
 async fn timeout_task(router: &'static mctp_estack::Router<'static>) -> ! {
     loop {
         let n = now();
         let delay = router.update_time(n).await.expect("time goes forwards");
+        reassembly::REASSEMBLY.expire();
         Timer::at(Instant::from_millis(delay + n)).await
     }
 }
 
+/// Sends MCTP Discovery Notify, prompting a bus owner to assign an EID.
+///
+/// Started by a `true` from `pending`, sent up to `DISCOVERY_ATTEMPTS`
+/// times until cancelled by `false` when an EID is assigned or USB goes
+/// down.
+#[embassy_executor::task]
+async fn discovery_task(
+    router: &'static Router<'static>,
+    pending: &'static SignalCS<bool>,
+) -> ! {
+    let mut active = false;
+    loop {
+        if !active {
+            active = pending.wait().await;
+            continue;
+        }
+
+        let notify = async {
+            for _ in 0..DISCOVERY_ATTEMPTS {
+                discovery_notify(router).await;
+                Timer::after(DISCOVERY_INTERVAL).await;
+            }
+        };
+
+        if let Either::Second(a) = select(notify, pending.wait()).await {
+            // Cancelled, or restarted by a repeated USB up
+            active = a;
+        } else {
+            warn!(
+                "No EID assigned after {DISCOVERY_ATTEMPTS} Discovery Notify"
+            );
+            active = false;
+        }
+    }
+}
+
+/// Sends a Discovery Notify to the bus owner.
+///
+/// The response is not received. It comes from the bus owner's EID,
+/// which doesn't match a request to the null EID, and is discarded by
+/// the router. Set Endpoint ID shows that it arrived.
+async fn discovery_notify(router: &'static Router<'static>) {
+    const DISCOVERY_NOTIFY: u8 = 0x0d;
+    // Request bit, instance ID 0
+    let msg = [0x80, DISCOVERY_NOTIFY];
+
+    debug!("Sending Discovery Notify");
+    // All packets are routed to USB, where the bus owner is the host
+    let mut req = router.req(Eid(0));
+    if let Err(e) = req.send(mctp::MCTP_TYPE_CONTROL, &msg).await {
+        warn!("Discovery Notify failed: {e}");
+    }
+}
+
 #[embassy_executor::task]
 async fn control_task(
     router: &'static Router<'static>,
-    control_notify: &'static SignalCS<ControlEvent>,
+    listener: RouterAsyncListener<'static>,
+    types: Vec<MsgType, MAX_MSG_TYPES>,
+    control_notify: &'static ControlChannel,
 ) -> ! {
-    let mut l = router
-        .listener(mctp::MCTP_TYPE_CONTROL)
-        .expect("control listener");
-    let mut c = mctp_estack::control::MctpControl::new(router);
+    watchdog::LIVENESS
+        .monitor(
+            watchdog::Liveness::CONTROL,
+            control_loop(router, listener, &types, control_notify),
+        )
+        .await
+}
 
-    let mut types = Vec::<MsgType, 4>::new();
-    types.push(mctp::MCTP_TYPE_CONTROL).unwrap();
-    #[cfg(feature = "nvme-mi")]
-    types.push(mctp::MCTP_TYPE_NVME).unwrap();
+/// Largest control request handled.
+///
+/// Control requests are small, longer messages are rejected with an
+/// invalid length completion code.
+const CONTROL_MAX_MSG: usize = 256;
+
+/// Returns an `ERROR_INVALID_LENGTH` response to a control request.
+///
+/// Returns `None` if `msg` isn't a request.
+fn control_invalid_length(msg: &[u8]) -> Option<[u8; 3]> {
+    const RQ: u8 = 1 << 7;
+    const CC_ERROR_INVALID_LENGTH: u8 = 0x03;
+
+    let [hdr, cmd, ..] = msg else {
+        return None;
+    };
+    (hdr & RQ != 0).then_some([hdr & !RQ, *cmd, CC_ERROR_INVALID_LENGTH])
+}
 
-    c.set_message_types(&types).unwrap();
+/// Runs the MCTP Control Protocol, advertising `types` in Get Message
+/// Type Support.
+async fn control_loop(
+    router: &'static Router<'static>,
+    mut l: RouterAsyncListener<'static>,
+    types: &[MsgType],
+    control_notify: &'static ControlChannel,
+) -> ! {
+    let mut c = mctp_estack::control::MctpControl::new(router);
+
+    c.set_message_types(types).unwrap();
     c.set_uuid(&device_uuid());
 
+    // Next hop for routing commands, learnt from Set Endpoint ID
+    let mut bus_owner = None;
+
     info!("MCTP Control Protocol server listening");
-    let mut buf = [0u8; 256];
+    // Large enough for any reassembled message, so that oversized
+    // requests can be answered rather than dropped by the stack.
+    let mut buf = [0u8; mctp_estack::config::MAX_PAYLOAD];
     loop {
-        let Ok((_typ, _ic, msg, resp)) = l.recv(&mut buf).await else {
-            warn!("control recv err");
-            continue;
+        let (_typ, _ic, msg, mut resp) = match l.recv(&mut buf).await {
+            Ok(r) => r,
+            Err(mctp::Error::NoSpace) => {
+                warn!("control recv oversized, dropped");
+                continue;
+            }
+            Err(e) => {
+                warn!("control recv err: {e}");
+                continue;
+            }
         };
         info!(
             "control recv len {} from eid {}",
@@ -381,9 +1002,46 @@ async fn control_task(
             resp.remote_eid()
         );
 
+        if msg.len() > CONTROL_MAX_MSG {
+            warn!(
+                "control request len {} exceeds {CONTROL_MAX_MSG} from eid {}",
+                msg.len(),
+                resp.remote_eid()
+            );
+            if let Some(r) = control_invalid_length(msg) {
+                if let Err(e) = resp.send(&r).await {
+                    warn!("control invalid length reply failed: {e}");
+                }
+            }
+            continue;
+        }
+
+        let mut routing_rsp = [0u8; routing::ROUTING_RESP_LEN];
+        if let Some(r) =
+            routing::control_routing(msg, bus_owner, &mut routing_rsp)
+        {
+            if let Err(e) = resp.send(r).await {
+                warn!("control routing reply failed: {e}");
+            }
+            continue;
+        }
+
+        let mut vendor_rsp = [0u8; ccvendor::VENDOR_SUPPORT_RESP_LEN];
+        if let Some(r) = ccvendor::control_vendor_support(msg, &mut vendor_rsp)
+        {
+            if let Err(e) = resp.send(r).await {
+                warn!("control vendor support reply failed: {e}");
+            }
+            continue;
+        }
+
         match c.handle_async(msg, resp).await {
             Ok(None) => (),
-            Ok(Some(ev)) => control_notify.signal(ev),
+            Ok(Some(ev)) => {
+                let ControlEvent::SetEndpointId { bus_owner: b, .. } = ev;
+                bus_owner = Some(b);
+                control_notify.send(ev).await
+            }
             Err(e) => {
                 warn!("control handler error: {e}");
             }
@@ -393,49 +1051,79 @@ async fn control_task(
 
 #[cfg(feature = "nvme-mi")]
 #[embassy_executor::task]
-async fn nvme_mi_task(router: &'static Router<'static>) -> ! {
+async fn nvme_mi_task(
+    mut l: RouterAsyncListener<'static>,
+    routes: &'static Routes,
+    config: &'static nvmeconfig::NvmeConfig,
+) -> ! {
     use nvme_mi_dev::*;
-    let mut l = router
-        .listener(mctp::MCTP_TYPE_NVME)
-        .expect("NVME-MI listener");
-
-    let mut subsys = Subsystem::new(SubsystemInfo::environment());
-    let ppid = subsys.add_port(PortType::Pcie(PciePort::new())).unwrap();
-    let ctrlid0 = subsys.add_controller(ppid).unwrap();
-    let _ctrlid1 = subsys.add_controller(ppid).unwrap();
-
-    let size_blocks = 10_000_000_000_000_u64.div_ceil(512);
-    let nsid = subsys.add_namespace(size_blocks).unwrap();
-    subsys
-        .controller_mut(ctrlid0)
-        .attach_namespace(nsid)
-        .unwrap();
-
-    let twpid = subsys
-        .add_port(PortType::TwoWire(TwoWirePort::new()))
-        .unwrap();
+
+    let nvmeconfig::NvmeTopology {
+        mut subsys,
+        pcie_port: ppid,
+        twowire_port: twpid,
+        mut controllers,
+    } = config.build();
     let mut mep = ManagementEndpoint::new(twpid);
 
     debug!("NVMe-MI endpoint listening");
 
     let mut buf = [0u8; mctp_estack::config::MAX_PAYLOAD];
     loop {
-        let Ok((_typ, ic, msg, resp)) = l.recv(&mut buf).await else {
+        let Ok((_typ, ic, msg, mut resp)) = l.recv(&mut buf).await else {
             debug!("recv() failed");
             continue;
         };
 
         debug!("Handling NVMe-MI message: {msg:x?}");
+
+        if nvmeconfig::is_subsystem_reset(msg, ic) {
+            // Returns the subsystem and Management Endpoint to their
+            // initial state. The Management Endpoint is itself reset, so
+            // no response is sent.
+            info!("NVMe-MI: NVM Subsystem Reset");
+            // Port IDs are unchanged, so `ppid` remains valid
+            let topo = config.build();
+            subsys = topo.subsys;
+            controllers = topo.controllers;
+            mep = ManagementEndpoint::new(topo.twowire_port);
+            // The initial MTU is always in range
+            let _ = routes.set_usb_mtu(USB_MTU);
+            continue;
+        }
+
+        if let Some(rsp) = nvmeconfig::async_event_config_reject(msg, ic) {
+            info!("NVMe-MI: Asynchronous Event configuration unsupported");
+            if let Err(e) = resp.send_vectored(ic, &[&rsp]).await {
+                warn!("NVMe-MI: response failed: {e}");
+            }
+            continue;
+        }
+
+        // Controllers report the die temperature
+        if let Some(celsius) = stmutil::die_temperature() {
+            let kelvin = (celsius + 273).clamp(0, u16::MAX as i32);
+            for &c in &controllers {
+                subsys
+                    .controller_mut(c)
+                    .set_temperature(Temperature::Kelvin(kelvin as u16));
+            }
+        }
         mep.handle_async(&mut subsys, msg, ic, resp, async |cmd| match cmd {
             CommandEffect::SetMtu { port_id, mtus } => {
-                if port_id == ppid {
-                    // TODO: implement once PortLookup::by_eid trait takes a
-                    // non-mut reference.
-                    warn!("NVMe-MI: Set MTU Port ID {port_id:?} MTU {mtus}, not currently handled");
-                    Err(CommandEffectError::Unsupported)
-                } else {
+                if port_id != ppid {
                     warn!("NVMe-MI: Set MTU bad Port ID {port_id:?}");
                     Err(CommandEffectError::InternalError)
+                } else if routes.set_usb_mtu(mtus + MCTP_HEADER).is_err() {
+                    // Rejected rather than clamped, so that the MTU
+                    // recorded by nvme-mi-dev matches the router.
+                    warn!("NVMe-MI: Set MTU {mtus} out of range");
+                    Err(CommandEffectError::Unsupported)
+                } else {
+                    // NVMe-MI MTU is the MCTP transmission unit, excluding
+                    // the MCTP header.
+                    info!("NVMe-MI: Set MTU Port ID {port_id:?} MTU {mtus}");
+                    Ok(())
                 }
             }
             CommandEffect::SetSmbusFreq { .. } => {
@@ -455,66 +1143,139 @@ async fn nvme_mi_task(router: &'static Router<'static>) -> ! {
 #[embassy_executor::task]
 async fn bench_task(
     router: &'static mctp_estack::Router<'static>,
-    bench_trigger: &'static SignalCS<BenchRequest>,
+    control: &'static BenchControl,
 ) -> ! {
     debug!("mctp-bench send running");
 
+    // Each concurrent bench needs its own buffer, it is borrowed while
+    // the message is fragmented.
     #[link_section = ".sram2_uninit"]
-    static mut BUF: MaybeUninit<StaticCell<[u8; BENCH_LEN]>> =
-        MaybeUninit::uninit();
+    static mut BUF: MaybeUninit<
+        StaticCell<[[u8; BENCH_LEN]; ccvendor::BENCH_FANOUT]>,
+    > = MaybeUninit::uninit();
     #[allow(static_mut_refs)]
-    let buf = unsafe { BUF.write(StaticCell::new()) };
-    let buf = buf.init_with(|| [0u8; _]);
+    let bufs = unsafe { BUF.write(StaticCell::new()) };
+    let bufs = bufs.init_with(|| [[0u8; _]; _]);
+
+    let mut slots = 0..;
+    let senders = bufs
+        .each_mut()
+        .map(|buf| bench_sender(router, control, slots.next().unwrap(), buf));
+    let _ = join_array(senders).await;
+    unreachable!()
+}
 
+/// Runs benches for one `BenchControl` slot.
+async fn bench_sender(
+    router: &'static mctp_estack::Router<'static>,
+    control: &'static BenchControl,
+    slot: usize,
+    buf: &mut [u8],
+) -> ! {
     let mut bench = ccvendor::MctpBench::new(buf).unwrap();
+    let sc = &control.slots[slot];
 
     let mut next_req = None;
 
     loop {
         let bench_req = match next_req.take() {
             Some(r) => r,
-            None => bench_trigger.wait().await,
+            None => match select(sc.request.wait(), sc.stop.wait()).await {
+                Either::First(r) => r,
+                Either::Second(()) => {
+                    // Not running, report the previous bench
+                    sc.stopped.signal(bench.sent());
+                    continue;
+                }
+            },
         };
 
         let mut req = router.req(bench_req.dest);
-        req.tag_noexpire().unwrap();
+        if let Err(e) = req.tag_noexpire() {
+            // Only fails once a tag has been sent, not for a new channel
+            warn!("mctp-bench non-expiring tag failed: {e}, tag will expire");
+        }
 
+        let mode = if bench_req.echo {
+            ", echo"
+        } else if bench_req.latency {
+            ", latency"
+        } else {
+            ""
+        };
         info!(
-            "mctp-bench started to EID {}, {} messages, size {}",
-            bench_req.dest, bench_req.count, bench_req.len
+            "mctp-bench started to EID {}, {} messages, size {}{mode}",
+            bench_req.dest, bench_req.count, bench_req.len,
         );
+        if let Some(delay) = bench_req.delay {
+            info!("mctp-bench delay {}us", delay.as_micros());
+        }
         let send = async {
-            if let Err(e) =
-                bench.send(&mut req, bench_req.count, bench_req.len).await
-            {
-                warn!("bench failed: {e}");
+            let r = if bench_req.echo {
+                // A new expiring tag for each message
+                let new_req = || router.req(bench_req.dest);
+                bench.send_echo(new_req, &bench_req).await
+            } else {
+                bench.send(&mut req, &bench_req).await
+            };
+            if let Err(e) = r {
+                warn!("bench to EID {} failed: {e}", bench_req.dest);
             }
             info!(
-                "mctp-bench sent {} iterations successfully",
+                "mctp-bench to EID {} sent {}/{} messages",
+                bench_req.dest,
+                bench.sent(),
                 bench_req.count
             );
+            if r.is_ok() {
+                ccvendor::BenchStatus::Complete
+            } else {
+                ccvendor::BenchStatus::Failed
+            }
         };
 
-        // Cancel the send loop when we receive a new request.
+        // Cancel the send loop when we receive a new request or stop.
         let stopped = async {
             debug_assert!(next_req.is_none());
-            next_req = Some(bench_trigger.wait().await);
-            debug!("New bench request");
+            if let Either::First(r) =
+                select(sc.request.wait(), sc.stop.wait()).await
+            {
+                next_req = Some(r);
+                debug!("New bench request");
+            }
         };
 
-        select(send, stopped).await;
-    }
-}
-
-#[embassy_executor::task]
-pub(crate) async fn blink_task(mut led: gpio::Output<'static>) {
-    loop {
-        trace!("led high");
-        led.set_high();
-        Timer::after(Duration::from_millis(2000)).await;
+        let busy = LED_STATUS.busy();
+        let r = select(send, stopped).await;
+        drop(busy);
+        // Release the non-expiring tag, which would otherwise leak on
+        // each bench until the peer has no free tags.
+        req.async_drop().await;
+        let status = match r {
+            Either::First(status) => status,
+            Either::Second(()) => ccvendor::BenchStatus::Stopped,
+        };
+        let stopped = matches!(status, ccvendor::BenchStatus::Stopped);
+        if stopped && next_req.is_none() {
+            info!(
+                "mctp-bench to EID {} stopped after {} messages",
+                bench_req.dest,
+                bench.sent()
+            );
+            sc.stopped.signal(bench.sent());
+        }
 
-        trace!("led low");
-        led.set_low();
-        Timer::after(Duration::from_millis(2000)).await;
+        // A new channel, the bench tag may have been released. Bounded,
+        // since the send can't complete while USB is down.
+        let mut result_req = router.req(bench_req.dest);
+        let result = bench.send_result(&mut result_req, &bench_req, status);
+        match with_timeout(BENCH_RESULT_TIMEOUT, result).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => debug!("mctp-bench result not sent: {e}"),
+            Err(_) => debug!("mctp-bench result timed out"),
+        }
+        if next_req.is_none() {
+            control.finish(slot);
+        }
     }
 }
@@ -3,21 +3,25 @@
  * Copyright (c) 2025 Code Construct
  */
 #![allow(clippy::collapsible_if)]
-use core::cell::Cell;
+use core::cell::{Cell, RefCell};
 use core::fmt::Write;
 use core::mem::MaybeUninit;
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 
-use log::{Log, Metadata, Record};
+use log::{LevelFilter, Log, Metadata, Record};
 use rtt_target::{rprintln, rtt_init_print};
 
 pub use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 pub use embassy_sync::channel::Channel;
+use embassy_time::{with_deadline, Duration, Instant};
+use embassy_usb::driver::EndpointError;
 
-use heapless::String;
+use heapless::{Deque, String, Vec};
 use static_cell::StaticCell;
 
-use crate::now;
+use crate::now_us;
 
 /// Set LOG_STACK_SIZE environment variable at build time to print
 /// difference from initial stack size in each log message.
@@ -26,15 +30,82 @@ const LOG_STACK_SIZE: bool = option_env!("LOG_STACK_SIZE").is_some();
 // Aribtrary limits, limited by RAM
 const MAX_LINE: usize = 120;
 pub const SERIAL_BACKLOG: usize = 50;
+/// Lines kept in RAM, retrievable with `MultiLog::ring_read()`
+pub const RING_LINES: usize = 32;
+/// Time lines interrupted by a USB serial detach are held for resending
+/// on reconnect. Older lines are dropped.
+const SERIAL_HOLD: Duration = Duration::from_secs(60);
+/// Lines logged within this time of the first are sent to USB serial
+/// in one write, up to `SERIAL_BATCH` bytes.
+const SERIAL_COALESCE: Duration = Duration::from_millis(2);
+const SERIAL_BATCH: usize = 512;
+/// Appended to a USB serial line missing the trailing `\r`
+const TRUNCATED: &[u8] = b" (line truncated)\r";
+const _: () = assert!(SERIAL_BATCH >= MAX_LINE + TRUNCATED.len());
+
+/// Bytes of log text preserved across reset, in `LOG_RAM`
+pub const PERSIST_LEN: usize = 4096 - 12;
+const PERSIST_MAGIC: u32 = u32::from_le_bytes(*b"PLOG");
 
 pub type RawMutex = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 type Line = String<MAX_LINE>;
+type Batch = Vec<u8, SERIAL_BATCH>;
 
 // sram2 is not zeroed at boot, so need MaybeUninit.
 #[link_section = ".sram2_uninit"]
 static mut LOGGER: MaybeUninit<StaticCell<MultiLog>> = MaybeUninit::uninit();
 static LOGGER_INIT: AtomicBool = AtomicBool::new(false);
 
+/// Log text preserved across reset, a ring of `\n` terminated lines.
+#[repr(C)]
+struct PersistLog {
+    magic: u32,
+    /// Offset in `data` of the next byte written
+    pos: u32,
+    /// Non-zero once `data` has been filled
+    wrapped: u32,
+    data: [u8; PERSIST_LEN],
+}
+
+// Fits in LOG_RAM in memory.x
+const _: () = assert!(size_of::<PersistLog>() <= 4096);
+
+#[link_section = ".log_uninit"]
+static mut PERSIST: MaybeUninit<PersistLog> = MaybeUninit::uninit();
+
+/// Set after a panic is persisted, so that it is the last line.
+static PERSIST_FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Runtime log level, a `LevelFilter` as u8.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LevelFilter::Trace as u8);
+
+/// USB serial log level, a `LevelFilter` as u8.
+///
+/// Applies to the serial sink in addition to the runtime levels.
+static SERIAL_LEVEL: AtomicU8 = AtomicU8::new(SERIAL_DEFAULT as u8);
+
+/// Default `SERIAL_LEVEL`, avoiding filling the backlog with trace logs
+const SERIAL_DEFAULT: LevelFilter = LevelFilter::Debug;
+
+/// Module name selecting the serial sink level in `set_module_level()`
+/// requests.
+pub const SERIAL_TARGET: &str = "@serial";
+
+/// Maximum number of per-module level overrides
+pub const MAX_MODULE_LEVELS: usize = 4;
+/// Maximum length of a module prefix
+pub const MAX_MODULE_PREFIX: usize = 32;
+
+type ModulePrefix = String<MAX_MODULE_PREFIX>;
+
+/// Per-module log levels, overriding `LOG_LEVEL`.
+///
+/// Matched against the record target (module path) by prefix.
+static MODULE_LEVELS: BlockingMutex<
+    RawMutex,
+    RefCell<Vec<(ModulePrefix, LevelFilter), MAX_MODULE_LEVELS>>,
+> = BlockingMutex::new(RefCell::new(Vec::new()));
+
 #[allow(dead_code)]
 type UsbSerialSender = embassy_usb::class::cdc_acm::Sender<
     'static,
@@ -51,10 +122,107 @@ pub fn init() -> &'static MultiLog {
     let logger = logger.init_with(MultiLog::new);
     logger.start();
     log::set_logger(logger).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    set_level(level());
+    let prev = logger.previous.lock(|p| p.borrow().len());
+    if prev > 0 {
+        log::info!("Log from before reset, {prev} bytes");
+    }
     logger
 }
 
+/// Returns the runtime log level.
+pub fn level() -> LevelFilter {
+    let l = LOG_LEVEL.load(Ordering::Relaxed);
+    LevelFilter::iter()
+        .nth(l as usize)
+        .unwrap_or(LevelFilter::Trace)
+}
+
+/// Sets the runtime log level.
+///
+/// Levels above the build's `max_level_*` feature have no effect.
+pub fn set_level(level: LevelFilter) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Returns the USB serial log level.
+pub fn serial_level() -> LevelFilter {
+    let l = SERIAL_LEVEL.load(Ordering::Relaxed);
+    LevelFilter::iter()
+        .nth(l as usize)
+        .unwrap_or(SERIAL_DEFAULT)
+}
+
+/// Sets the USB serial log level, `None` for the default.
+///
+/// Only records enabled by the runtime levels reach the serial sink.
+/// Returns the previous level.
+pub fn set_serial_level(level: Option<LevelFilter>) -> LevelFilter {
+    let level = level.unwrap_or(SERIAL_DEFAULT);
+    let prev = SERIAL_LEVEL.swap(level as u8, Ordering::Relaxed);
+    LevelFilter::iter()
+        .nth(prev as usize)
+        .unwrap_or(SERIAL_DEFAULT)
+}
+
+/// Returns whether `target` is `prefix` or a submodule of it.
+fn module_matches(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Returns the log level for a target.
+///
+/// The longest matching module override applies, otherwise the
+/// runtime log level.
+pub fn target_level(target: &str) -> LevelFilter {
+    MODULE_LEVELS.lock(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(prefix, _)| module_matches(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, l)| *l)
+            .unwrap_or_else(level)
+    })
+}
+
+/// Sets the log level for a module prefix, such as `usbnvme::pldm`.
+///
+/// `None` removes an override. Returns the previous override.
+/// Fails if the prefix is too long or there are too many overrides.
+pub fn set_module_level(
+    prefix: &str,
+    level: Option<LevelFilter>,
+) -> Result<Option<LevelFilter>, ()> {
+    let prefix = ModulePrefix::try_from(prefix)?;
+    let prev = MODULE_LEVELS.lock(|m| {
+        let mut m = m.borrow_mut();
+        let pos = m.iter().position(|(p, _)| *p == prefix);
+        let prev = pos.map(|i| m[i].1);
+        match (pos, level) {
+            (Some(i), Some(l)) => m[i].1 = l,
+            (Some(i), None) => {
+                m.swap_remove(i);
+            }
+            (None, Some(l)) => m.push((prefix, l)).map_err(|_| ())?,
+            (None, None) => (),
+        }
+        Ok(prev)
+    })?;
+    update_max_level();
+    Ok(prev)
+}
+
+/// Sets the `log` crate's max level to allow the most verbose
+/// module or runtime level.
+fn update_max_level() {
+    let max = MODULE_LEVELS
+        .lock(|m| m.borrow().iter().map(|(_, l)| *l).fold(level(), Ord::max));
+    log::set_max_level(max);
+}
+
 /// Configure suitable for reporting a panic.
 pub fn enter_panic() {
     rtt_target::with_terminal_channel(|t| {
@@ -62,6 +230,73 @@ pub fn enter_panic() {
     })
 }
 
+/// Appends a panic message to the persistent log, to be read after
+/// reset. Later log lines aren't persisted.
+///
+/// Called from the panic handler. Doesn't allocate or take locks.
+pub fn persist_panic(info: &PanicInfo) {
+    PERSIST_FROZEN.store(true, Ordering::Relaxed);
+    let now = Timestamp(now_us());
+    let _ = writeln!(PersistWriter, "{} ERROR panicked. {}", now, info);
+}
+
+/// Appends to the persistent log.
+struct PersistWriter;
+
+impl Write for PersistWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        cortex_m::interrupt::free(|_| {
+            // Safety: only accessed with interrupts disabled, on a single
+            // core. Contents may be uninitialised before `init()`, all
+            // values are valid for the types and `pos` is bounded here.
+            let p = unsafe { &mut *addr_of_mut!(PERSIST).cast::<PersistLog>() };
+            let mut pos = p.pos as usize % PERSIST_LEN;
+            for &b in s.as_bytes() {
+                p.data[pos] = b;
+                pos += 1;
+                if pos == PERSIST_LEN {
+                    pos = 0;
+                    p.wrapped = 1;
+                }
+            }
+            p.pos = pos as u32;
+        });
+        Ok(())
+    }
+}
+
+/// Moves the persistent log from before reset to `prev`, and starts a
+/// new one.
+fn persist_take(prev: &mut Vec<u8, PERSIST_LEN>) {
+    cortex_m::interrupt::free(|_| {
+        // Safety: as for `PersistWriter`. Contents are validated by the
+        // magic.
+        let p = unsafe { &mut *addr_of_mut!(PERSIST).cast::<PersistLog>() };
+        let magic = unsafe { addr_of_mut!(p.magic).read_volatile() };
+        let pos = p.pos as usize;
+        if magic == PERSIST_MAGIC && pos < PERSIST_LEN && p.wrapped <= 1 {
+            let wrapped = p.wrapped != 0;
+            let (recent, oldest) = p.data.split_at(pos);
+            let oldest = if wrapped { oldest } else { &[] };
+            let text = || oldest.iter().chain(recent).copied();
+            // A wrapped log starts part way through a line
+            let skip = if wrapped {
+                text()
+                    .position(|b| b == b'\n')
+                    .map_or(PERSIST_LEN, |i| i + 1)
+            } else {
+                0
+            };
+            prev.clear();
+            // Length is at most PERSIST_LEN
+            prev.extend(text().skip(skip));
+        }
+        p.magic = PERSIST_MAGIC;
+        p.pos = 0;
+        p.wrapped = 0;
+    })
+}
+
 #[embassy_executor::task]
 pub async fn log_usbserial_task(
     mut sender: UsbSerialSender,
@@ -71,41 +306,93 @@ pub async fn log_usbserial_task(
     async fn write_cdc(
         sender: &mut UsbSerialSender,
         b: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), EndpointError> {
         for pkt in b.chunks(64) {
             if let Err(e) = sender.write_packet(pkt).await {
                 rprintln!("usbserial err {:?}", e);
-                return Err(());
+                return Err(e);
             }
         }
         // cdc acm zero length packet
         if b.len().is_multiple_of(64) {
-            sender.write_packet(&[]).await.map_err(|_| ())?;
+            sender.write_packet(&[]).await?;
         }
         Ok(())
     }
 
+    /// Appends a line, marking it if truncated.
+    fn push_line(batch: &mut Batch, s: &Line) {
+        // Space is checked by the caller
+        let _ = batch.extend_from_slice(s.as_bytes());
+        if !s.ends_with("\r") {
+            let _ = batch.extend_from_slice(TRUNCATED);
+        }
+    }
+
+    /// Whether any further line fits in the batch.
+    fn has_room(batch: &Batch) -> bool {
+        batch.capacity() - batch.len() >= MAX_LINE + TRUNCATED.len()
+    }
+
+    // A batch interrupted by a detach, and when it was interrupted
+    let mut held: Option<(Batch, Instant)> = None;
+
     // Outer loop for reattaching USB
     loop {
         sender.wait_connection().await;
+        if held
+            .as_ref()
+            .is_some_and(|(_, t)| t.elapsed() >= SERIAL_HOLD)
+        {
+            held = None;
+        }
         // inner loop writing log lines while connected
         'connected: loop {
-            let s = logger.serial_backlog.receive().await;
-            if write_cdc(&mut sender, s.as_bytes()).await.is_err() {
-                break 'connected;
-            }
-            if !s.ends_with("\r") {
-                if write_cdc(&mut sender, b" (line truncated)\r")
-                    .await
-                    .is_err()
-                {
+            let batch = match held.take() {
+                Some((b, _)) => b,
+                None => {
+                    let mut batch = Batch::new();
+                    push_line(
+                        &mut batch,
+                        &logger.serial_backlog.receive().await,
+                    );
+
+                    // Coalesce lines logged soon after
+                    let deadline = Instant::now() + SERIAL_COALESCE;
+                    while has_room(&batch) {
+                        let next = logger.serial_backlog.receive();
+                        let Ok(next) = with_deadline(deadline, next).await
+                        else {
+                            break;
+                        };
+                        push_line(&mut batch, &next);
+                    }
+                    batch
+                }
+            };
+            match write_cdc(&mut sender, &batch).await {
+                Ok(()) => (),
+                // Detached, resend the whole batch on reconnect
+                Err(EndpointError::Disabled) => {
+                    held = Some((batch, Instant::now()));
                     break 'connected;
                 }
+                // Other errors drop the batch
+                Err(_) => break 'connected,
             }
         }
     }
 }
 
+/// Log timestamp, formatted as seconds with microseconds.
+struct Timestamp(u64);
+
+impl core::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:6}.{:06}", self.0 / 1_000_000, self.0 % 1_000_000)
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 enum LostLine {
     No,
@@ -113,9 +400,42 @@ enum LostLine {
     Warned,
 }
 
+/// Most recent log lines, oldest are overwritten.
+struct RingLog {
+    lines: Deque<Line, RING_LINES>,
+    /// Sequence number of the first line in `lines`
+    first: u32,
+}
+
+impl RingLog {
+    const fn new() -> Self {
+        Self {
+            lines: Deque::new(),
+            first: 0,
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        if self.lines.is_full() {
+            self.lines.pop_front();
+            self.first = self.first.wrapping_add(1);
+        }
+        // Space was made above
+        let _ = self.lines.push_back(line);
+    }
+
+    /// Sequence number following the last line
+    fn end(&self) -> u32 {
+        self.first.wrapping_add(self.lines.len() as u32)
+    }
+}
+
 pub struct MultiLog {
     serial_backlog: Channel<RawMutex, Line, SERIAL_BACKLOG>,
     serial_lost_lines: BlockingMutex<RawMutex, Cell<LostLine>>,
+    ring: BlockingMutex<RawMutex, RefCell<RingLog>>,
+    /// Persistent log from before the most recent reset
+    previous: BlockingMutex<RawMutex, RefCell<Vec<u8, PERSIST_LEN>>>,
     msp_top: AtomicU32,
 }
 
@@ -124,6 +444,8 @@ impl MultiLog {
         Self {
             serial_backlog: Channel::new(),
             serial_lost_lines: BlockingMutex::new(Cell::new(LostLine::No)),
+            ring: BlockingMutex::new(RefCell::new(RingLog::new())),
+            previous: BlockingMutex::new(RefCell::new(Vec::new())),
             msp_top: AtomicU32::new(0),
         }
     }
@@ -131,12 +453,23 @@ impl MultiLog {
     fn start(&self) {
         self.msp_top
             .store(cortex_m::register::msp::read(), Ordering::Relaxed);
+        self.previous.lock(|p| persist_take(&mut p.borrow_mut()));
         rtt_init_print!(rtt_target::ChannelMode::NoBlockTrim, 4096);
     }
 
+    /// Queues a line for USB serial, regardless of log levels.
+    ///
+    /// Waits for space in the backlog.
+    #[cfg(feature = "usb-shell")]
+    pub async fn serial_print(&self, args: core::fmt::Arguments<'_>) {
+        let mut s = Line::new();
+        // Truncated writes will be reported by the other end, detecting \r
+        let _ = write!(&mut s, "{args}\r");
+        self.serial_backlog.send(s).await
+    }
+
     fn log_usbserial(&self, record: &Record, msg: Line) {
-        if record.level() >= log::Level::Trace {
-            // Avoid filling queue with trace logs
+        if record.level() > serial_level() {
             return;
         }
 
@@ -161,12 +494,97 @@ impl MultiLog {
             }
         });
     }
+
+    fn log_ring(&self, record: &Record, msg: &Line) {
+        if record.level() >= log::Level::Trace {
+            // Avoid trace logs displacing more useful lines
+            return;
+        }
+
+        self.ring.lock(|r| {
+            r.borrow_mut().push(msg.clone());
+            // In the same critical section, so lines aren't interleaved
+            if !PERSIST_FROZEN.load(Ordering::Relaxed) {
+                let _ =
+                    writeln!(PersistWriter, "{}", msg.trim_end_matches('\r'));
+            }
+        });
+    }
+
+    /// Reads the log persisted before the most recent reset, starting at
+    /// byte `offset`.
+    ///
+    /// Returns `(total, len)`, the length of the whole previous log and
+    /// the length copied to `buf`. The log is empty after a power on.
+    pub fn previous_read(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> (usize, usize) {
+        self.previous.lock(|p| {
+            let p = p.borrow();
+            let rest = p.get(offset..).unwrap_or_default();
+            let l = rest.len().min(buf.len());
+            buf[..l].copy_from_slice(&rest[..l]);
+            (p.len(), l)
+        })
+    }
+
+    /// Reads recent log lines, starting from sequence number `start`.
+    ///
+    /// Whole lines are copied to `buf`, each terminated by `\n`.
+    /// Returns `(first, next, len)`. `first` is the sequence number of the
+    /// first line copied, greater than `start` if lines have been
+    /// overwritten. `next` is the sequence number to read next, equal to
+    /// `first` when there are no more lines.
+    pub fn ring_read(&self, start: u32, buf: &mut [u8]) -> (u32, u32, usize) {
+        const TRUNCATED: &[u8] = b" (line truncated)";
+
+        self.ring.lock(|r| {
+            let r = r.borrow();
+            // Handles wraparound of sequence numbers
+            let skip = start.wrapping_sub(r.first) as usize;
+            let skip = if skip > r.lines.len() {
+                if start.wrapping_sub(r.end()) as i32 > 0 {
+                    // Ahead of the log
+                    r.lines.len()
+                } else {
+                    // Lines were overwritten
+                    0
+                }
+            } else {
+                skip
+            };
+
+            let first = r.first.wrapping_add(skip as u32);
+            let mut next = first;
+            let mut len = 0;
+            for l in r.lines.iter().skip(skip) {
+                // Complete lines end with \r, as for the serial log
+                let (l, trunc) = match l.strip_suffix('\r') {
+                    Some(l) => (l.as_bytes(), &[][..]),
+                    None => (l.as_bytes(), TRUNCATED),
+                };
+                let end = len + l.len() + trunc.len() + 1;
+                let Some(dest) = buf.get_mut(len..end) else {
+                    break;
+                };
+                let (d, rest) = dest.split_at_mut(l.len());
+                d.copy_from_slice(l);
+                let (d, rest) = rest.split_at_mut(trunc.len());
+                d.copy_from_slice(trunc);
+                rest[0] = b'\n';
+                len = end;
+                next = next.wrapping_add(1);
+            }
+            (first, next, len)
+        })
+    }
 }
 
 impl Log for MultiLog {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        // TODO filtering
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= target_level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
@@ -174,30 +592,31 @@ impl Log for MultiLog {
             return;
         }
 
-        let now = now();
+        let now = Timestamp(now_us());
         if LOG_STACK_SIZE {
             let stack = self.msp_top.load(Ordering::Relaxed)
                 - cortex_m::register::msp::read();
             rprintln!(
-                "{:10} {:<5} {:08x} {}",
+                "{} {:<5} {:08x} {}",
                 now,
                 record.level(),
                 stack,
                 record.args()
             );
         } else {
-            rprintln!("{:10} {:<5} {}", now, record.level(), record.args());
+            rprintln!("{} {:<5} {}", now, record.level(), record.args());
         }
 
         let mut s = Line::new();
         // Truncated writes will be reported by the other end, detecting \r
         let _ = write!(
             &mut s,
-            "{:10} {:<5} {} \r",
+            "{} {:<5} {} \r",
             now,
             record.level(),
             record.args()
         );
+        self.log_ring(record, &s);
         self.log_usbserial(record, s);
     }
 
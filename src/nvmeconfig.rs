@@ -0,0 +1,348 @@
+//! Emulated NVMe-MI subsystem topology.
+//!
+//! A table of profiles describes the controllers, namespaces and
+//! identifiers. The profile is selected by persistent settings, so one
+//! image can present different topologies. Other topologies can be
+//! described by a record in the settings instead.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use heapless::Vec;
+use mctp::MsgIC;
+use nvme_mi_dev::{
+    ControllerId, PciePort, PortId, PortType, Subsystem, SubsystemInfo,
+    TwoWirePort,
+};
+use static_cell::StaticCell;
+
+use crate::settings::{
+    NvmeTopologySettings, Settings, MAX_TOPOLOGY_NAMESPACES,
+};
+
+/// Limits of `nvme_mi_dev::Subsystem`
+const MAX_CONTROLLERS: usize = 2;
+const MAX_NAMESPACES: usize = 4;
+
+const _: () = assert!(MAX_TOPOLOGY_NAMESPACES <= MAX_NAMESPACES);
+
+/// Attached controller indices, indexed by a bitmask of controllers
+const ATTACHED: [&[usize]; 1 << MAX_CONTROLLERS] = [&[], &[0], &[1], &[0, 1]];
+
+/// Largest namespace, in 512 byte blocks (512 TiB)
+const MAX_BLOCKS: u64 = 1 << 40;
+
+const TB: u64 = 1_000_000_000_000;
+
+/// NVMe-MI Message Integrity Check, CRC-32C
+const MIC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// MCTP message type byte covered by the MIC, with IC set
+const MIC_TYPE: u8 = 0x80 | mctp::MCTP_TYPE_NVME.0;
+
+/// NMIMT for an NVMe-MI Command
+const NMIMT_MI_COMMAND: u8 = 0x01;
+/// NVMe-MI Command opcodes
+const OPCODE_CONFIG_SET: u8 = 0x03;
+const OPCODE_CONFIG_GET: u8 = 0x04;
+const OPCODE_RESET: u8 = 0x07;
+/// Reset command Reset Type, in NMD0 bits 31:24
+const RESET_TYPE_SUBSYSTEM: u8 = 0x00;
+/// Configuration Identifier, in NMD0 bits 7:0
+const CONFIG_ASYNC_EVENT: u8 = 0x04;
+/// NVMe-MI Response Message Status
+const STATUS_INVALID_PARAMETER: u8 = 0x04;
+
+/// NVMe-MI response with no response data, including the MIC
+pub const MI_STATUS_RESP_LEN: usize = 11;
+
+#[derive(Debug)]
+pub struct NamespaceConfig {
+    /// Capacity in 512 byte blocks
+    pub blocks: u64,
+    /// Indices of the controllers the namespace is attached to
+    pub attached: &'static [usize],
+}
+
+#[derive(Debug)]
+pub struct NvmeConfig {
+    pub name: &'static str,
+    /// PCI subsystem device ID, distinguishing profiles to a host.
+    /// `None` uses the build environment `NVME_MI_DEV_PCI_SDID`.
+    pub pci_sdid: Option<u16>,
+    /// Controllers on the PCIe port
+    pub controllers: usize,
+    pub namespaces: &'static [NamespaceConfig],
+}
+
+/// Selectable profiles, indexed by `Settings::nvme_profile`.
+pub const PROFILES: &[NvmeConfig] = &[
+    NvmeConfig {
+        name: "default",
+        pci_sdid: None,
+        controllers: 2,
+        namespaces: &[NamespaceConfig {
+            blocks: (10 * TB).div_ceil(512),
+            attached: &[0],
+        }],
+    },
+    NvmeConfig {
+        name: "namespace-per-controller",
+        pci_sdid: Some(0x0001),
+        controllers: 2,
+        namespaces: &[
+            NamespaceConfig {
+                blocks: TB.div_ceil(512),
+                attached: &[0],
+            },
+            NamespaceConfig {
+                blocks: TB.div_ceil(512),
+                attached: &[1],
+            },
+        ],
+    },
+    NvmeConfig {
+        name: "shared-namespace",
+        pci_sdid: Some(0x0002),
+        controllers: 2,
+        namespaces: &[NamespaceConfig {
+            blocks: (4 * TB).div_ceil(512),
+            attached: &[0, 1],
+        }],
+    },
+    NvmeConfig {
+        name: "single-controller",
+        pci_sdid: Some(0x0003),
+        controllers: 1,
+        namespaces: &[
+            NamespaceConfig {
+                blocks: (TB / 4).div_ceil(512),
+                attached: &[0],
+            },
+            NamespaceConfig {
+                blocks: (TB / 4).div_ceil(512),
+                attached: &[0],
+            },
+            NamespaceConfig {
+                blocks: (TB / 4).div_ceil(512),
+                attached: &[0],
+            },
+            NamespaceConfig {
+                blocks: (TB / 4).div_ceil(512),
+                attached: &[0],
+            },
+        ],
+    },
+];
+
+const _: () = {
+    let mut i = 0;
+    while i < PROFILES.len() {
+        assert!(PROFILES[i].valid());
+        i += 1;
+    }
+};
+
+impl NvmeConfig {
+    /// Returns whether the topology fits `nvme_mi_dev` limits, with
+    /// sane namespace sizes attached to existing controllers.
+    const fn valid(&self) -> bool {
+        if self.controllers == 0 || self.controllers > MAX_CONTROLLERS {
+            return false;
+        }
+        if self.namespaces.len() > MAX_NAMESPACES {
+            return false;
+        }
+        let mut n = 0;
+        while n < self.namespaces.len() {
+            let ns = &self.namespaces[n];
+            if ns.blocks == 0 || ns.blocks > MAX_BLOCKS {
+                return false;
+            }
+            let mut a = 0;
+            while a < ns.attached.len() {
+                if ns.attached[a] >= self.controllers {
+                    return false;
+                }
+                a += 1;
+            }
+            n += 1;
+        }
+        true
+    }
+
+    /// Returns the topology from `settings`, otherwise the selected
+    /// profile.
+    ///
+    /// Called once at startup.
+    pub fn select(settings: &Settings) -> &'static Self {
+        if let Some(t) = &settings.nvme_topology {
+            match Self::from_settings(t) {
+                Some(c) => return c,
+                None => warn!("Invalid NVMe topology {t:?}, using profile"),
+            }
+        }
+        Self::profile(settings.nvme_profile.unwrap_or(0))
+    }
+
+    /// Returns a topology read from settings, or `None` if it is not
+    /// `valid()`.
+    fn from_settings(t: &NvmeTopologySettings) -> Option<&'static Self> {
+        static NAMESPACES: StaticCell<Vec<NamespaceConfig, MAX_NAMESPACES>> =
+            StaticCell::new();
+        static CONFIG: StaticCell<NvmeConfig> = StaticCell::new();
+
+        let mut namespaces = Vec::new();
+        for &(blocks, attached) in &t.namespaces {
+            let attached = ATTACHED.get(attached as usize)?;
+            // Capacity matches, asserted with MAX_TOPOLOGY_NAMESPACES
+            let _ = namespaces.push(NamespaceConfig { blocks, attached });
+        }
+        let config = Self {
+            name: "flash",
+            pci_sdid: t.pci_sdid,
+            controllers: t.controllers as usize,
+            namespaces: NAMESPACES.init(namespaces),
+        };
+        config.valid().then(|| &*CONFIG.init(config))
+    }
+
+    /// Returns the profile at `index`, or the default if out of range.
+    pub fn profile(index: u8) -> &'static Self {
+        PROFILES.get(index as usize).unwrap_or_else(|| {
+            warn!("Unknown NVMe profile {index}, using default");
+            &PROFILES[0]
+        })
+    }
+
+    /// Builds the subsystem.
+    pub fn build(&self) -> NvmeTopology {
+        let mut info = SubsystemInfo::environment();
+        if let Some(sdid) = self.pci_sdid {
+            info.pci_sdid = sdid;
+        }
+
+        // Limits were checked by valid()
+        let mut subsys = Subsystem::new(info);
+        let ppid = subsys.add_port(PortType::Pcie(PciePort::new())).unwrap();
+        let mut controllers = Vec::new();
+        for _ in 0..self.controllers {
+            let c = subsys.add_controller(ppid).unwrap();
+            controllers.push(c).unwrap();
+        }
+
+        for ns in self.namespaces {
+            let nsid = subsys.add_namespace(ns.blocks).unwrap();
+            for &c in ns.attached {
+                subsys
+                    .controller_mut(controllers[c])
+                    .attach_namespace(nsid)
+                    .unwrap();
+            }
+        }
+
+        let twpid = subsys
+            .add_port(PortType::TwoWire(TwoWirePort::new()))
+            .unwrap();
+
+        info!(
+            "NVMe profile {}: {} controllers, {} namespaces",
+            self.name,
+            self.controllers,
+            self.namespaces.len()
+        );
+        NvmeTopology {
+            subsys,
+            pcie_port: ppid,
+            twowire_port: twpid,
+            controllers,
+        }
+    }
+}
+
+pub struct NvmeTopology {
+    pub subsys: Subsystem,
+    pub pcie_port: PortId,
+    pub twowire_port: PortId,
+    pub controllers: Vec<ControllerId, MAX_CONTROLLERS>,
+}
+
+/// An NVMe-MI Command request for command slot 0.
+struct MiCommand {
+    /// First byte of the message header
+    hdr: u8,
+    opcode: u8,
+    nmd0: [u8; 4],
+}
+
+/// Returns the NVMe-MI Command in `msg`, if it has a valid integrity
+/// check.
+fn mi_command(msg: &[u8], ic: MsgIC) -> Option<MiCommand> {
+    if !ic.0 {
+        return None;
+    }
+    let (msg, mic) = msg.split_last_chunk::<4>()?;
+    // Message header, opcode and NMD0
+    let &[hdr, _, _, opcode, _, _, _, n0, n1, n2, n3, ..] = msg else {
+        return None;
+    };
+
+    let mut digest = MIC.digest();
+    digest.update(&[MIC_TYPE]);
+    digest.update(msg);
+    if digest.finalize() != u32::from_le_bytes(*mic) {
+        return None;
+    }
+
+    // Request, command slot 0
+    let nmimt = (hdr >> 3) & 0x0f;
+    (hdr & 0x81 == 0 && nmimt == NMIMT_MI_COMMAND).then_some(MiCommand {
+        hdr,
+        opcode,
+        nmd0: [n0, n1, n2, n3],
+    })
+}
+
+/// Returns whether `msg` is an NVMe-MI Reset command for an NVM
+/// Subsystem Reset, with a valid integrity check.
+///
+/// `nvme_mi_dev` doesn't implement Reset, so it is handled by the caller.
+pub fn is_subsystem_reset(msg: &[u8], ic: MsgIC) -> bool {
+    mi_command(msg, ic).is_some_and(|c| {
+        c.opcode == OPCODE_RESET && c.nmd0[3] == RESET_TYPE_SUBSYSTEM
+    })
+}
+
+/// Returns an Invalid Parameter response if `msg` is a Configuration
+/// Set or Get of the Asynchronous Event configuration.
+///
+/// Asynchronous Event Messages aren't supported, and the ports don't
+/// advertise them. `nvme_mi_dev` doesn't implement this configuration,
+/// so it is handled by the caller.
+pub fn async_event_config_reject(
+    msg: &[u8],
+    ic: MsgIC,
+) -> Option<[u8; MI_STATUS_RESP_LEN]> {
+    let c = mi_command(msg, ic)?;
+    if !matches!(c.opcode, OPCODE_CONFIG_SET | OPCODE_CONFIG_GET)
+        || c.nmd0[0] != CONFIG_ASYNC_EVENT
+    {
+        return None;
+    }
+
+    // Message header with ROR set, status, and an empty NVMe Management
+    // Response
+    let mut rsp = [0u8; MI_STATUS_RESP_LEN];
+    rsp[0] = c.hdr | 0x80;
+    rsp[3] = STATUS_INVALID_PARAMETER;
+    let (body, mic) = rsp.split_at_mut(MI_STATUS_RESP_LEN - 4);
+    let mut digest = MIC.digest();
+    digest.update(&[MIC_TYPE]);
+    digest.update(body);
+    mic.copy_from_slice(&digest.finalize().to_le_bytes());
+    Some(rsp)
+}
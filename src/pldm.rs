@@ -15,14 +15,18 @@ use static_cell::StaticCell;
 use pldm_file::PLDM_TYPE_FILE_TRANSFER;
 use pldm_platform::proto::PdrRecord;
 
-use crate::SharedHash;
-use embassy_futures::select::select;
-use embassy_time::Duration;
+use crate::flash::RegionWriter;
+use crate::led::LED_STATUS;
+use crate::{SharedFlash, SharedHash};
+use embassy_futures::select::select3;
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Timer};
 use mctp::{AsyncReqChannel, Eid};
 use mctp_estack::Router;
 use pldm::control::{requester as ctrq, PLDM_TYPE_CONTROL};
 use pldm::{proto_error, PldmError, PldmResult};
 use pldm_platform::requester as platrq;
+use xspiflash::crc::Crc32;
 
 use crate::SignalCS;
 
@@ -47,18 +51,64 @@ pub trait PldmTimeout: Future + Sized {
 
 impl<F: Future> PldmTimeout for F {}
 
+/// Number of attempts for retried requests
+const RETRIES: u8 = 3;
+/// Delay before a retry, multiplied by the attempt number
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Runs a request, retrying on error or timeout.
+///
+/// `make_future` is called for each of up to `attempts` tries, each
+/// limited to `timeout`. Only suitable for requests that are safe to repeat.
+async fn with_retries<T>(
+    attempts: u8,
+    timeout: Duration,
+    mut make_future: impl AsyncFnMut() -> PldmResult<T>,
+) -> PldmResult<T> {
+    let mut attempt = 1;
+    loop {
+        let e = match make_future().with_timeout(timeout).await {
+            Ok(Ok(v)) => return Ok(v),
+            Ok(Err(e)) => e,
+            Err(t) => t.into(),
+        };
+        if attempt >= attempts {
+            return Err(e);
+        }
+        warn!("PLDM request failed: {e}. Retry {attempt}/{}", attempts - 1);
+        Timer::after(RETRY_BACKOFF * attempt as u32).await;
+        attempt += 1;
+    }
+}
+
+// File Descriptor PDRs retained from the responder
+const MAX_FILES: usize = 4;
+
 // Limited by MCTP message size, must be power of two
 const PART_SIZE: usize = 4096;
 // sram2 is not zeroed at boot, so need MaybeUninit.
 #[link_section = ".sram2_uninit"]
 static mut PART_BUF: MaybeUninit<StaticCell<[u8; PART_SIZE + 18]>> =
     MaybeUninit::uninit();
+/// Received data held until written to flash.
+#[link_section = ".sram2_uninit"]
+static mut WRITE_BUF: MaybeUninit<StaticCell<[u8; PART_SIZE]>> =
+    MaybeUninit::uninit();
+
+/// Wait before retrying a transfer that failed for lack of a free MCTP
+/// tag. Longer than the mctp-estack flow expiry, so that tags held by
+/// unanswered requests are freed.
+const TAG_BACKOFF: Duration = Duration::from_secs(7);
+/// Retries after failing for lack of a tag, for each Set Endpoint ID.
+const TAG_RETRIES: usize = 3;
 
 #[embassy_executor::task]
 pub(crate) async fn pldm_file_task(
     router: &'static Router<'static>,
     peer: &'static SignalCS<Eid>,
+    link_down: &'static SignalCS<()>,
     hash: &'static SharedHash,
+    flash: &'static SharedFlash,
 ) -> ! {
     info!("PLDM file task started");
 
@@ -66,33 +116,91 @@ pub(crate) async fn pldm_file_task(
     #[allow(static_mut_refs)]
     let part_buf = unsafe { PART_BUF.write(StaticCell::new()) };
     let part_buf = part_buf.init_with(|| [0u8; _]);
+    // Safety: as for PART_BUF
+    #[allow(static_mut_refs)]
+    let write_buf = unsafe { WRITE_BUF.write(StaticCell::new()) };
+    let write_buf = write_buf.init_with(|| [0u8; _]);
 
     let mut host = None;
+    // Progress of an interrupted transfer
+    let mut resume = None;
+    let mut tag_retries = 0;
     loop {
         let target = match host.take() {
             Some(t) => t,
-            None => peer.wait().await,
+            None => {
+                tag_retries = 0;
+                peer.wait().await
+            }
         };
 
         info!("Running PLDM file transfer from {target}");
+        // Only a disconnect during this transfer applies
+        link_down.reset();
 
+        let mut retry = false;
         let run = async {
-            if let Err(e) = pldm_run_file(target, router, hash, part_buf).await
+            match pldm_run_file(
+                target,
+                router,
+                hash,
+                flash,
+                part_buf,
+                write_buf,
+                &mut resume,
+            )
+            .await
             {
-                warn!("Error running file transfer: {e}");
+                Ok(()) => (),
+                Err(PldmError::Mctp(mctp::Error::TagUnavailable))
+                    if tag_retries < TAG_RETRIES =>
+                {
+                    tag_retries += 1;
+                    warn!(
+                        "No free MCTP tag for {target}, retrying in {}s",
+                        TAG_BACKOFF.as_secs()
+                    );
+                    Timer::after(TAG_BACKOFF).await;
+                    retry = true;
+                }
+                Err(e) => {
+                    warn!("Error running file transfer: {e}");
+                    LED_STATUS.fault();
+                }
             }
         };
 
-        // A subsequent Set Endpoint ID will interrupt the transfer.
-        // TODO: Revisit this once we have timeouts
+        // A subsequent Set Endpoint ID will interrupt the transfer,
+        // which then resumes from the last part written.
         let setendpoint = async {
             host = Some(peer.wait().await);
         };
 
-        select(run, setendpoint).await;
+        // USB disconnect abandons the transfer. Requests are dropped,
+        // releasing their tags, and the next Set Endpoint ID after
+        // reconnecting resumes the transfer.
+        let disconnect = async {
+            link_down.wait().await;
+            info!("PLDM file transfer abandoned on USB disconnect");
+        };
+
+        let _busy = LED_STATUS.busy();
+        select3(run, setendpoint, disconnect).await;
+        if retry && host.is_none() {
+            host = Some(target);
+        }
     }
 }
 
+/// Progress of an interrupted file transfer.
+#[derive(Debug, Clone, Copy)]
+struct Resume {
+    file_identifier: u16,
+    file_size: usize,
+    /// Bytes received and written to the staging area
+    offset: usize,
+}
+
 async fn check_version(
     comm: &mut impl AsyncReqChannel,
     pldm_type: u8,
@@ -158,7 +266,10 @@ async fn pldm_run_file(
     eid: Eid,
     router: &'static Router<'static>,
     hash: &'static SharedHash,
+    flash: &'static SharedFlash,
     part_buf: &mut [u8],
+    write_buf: &mut [u8; PART_SIZE],
+    resume: &mut Option<Resume>,
 ) -> Result<(), PldmError> {
     use pldm_file::client::*;
     use pldm_file::proto::*;
@@ -168,27 +279,44 @@ async fn pldm_run_file(
     const PLDM_FILE_VERSION: u32 = 0xf1f0f000;
 
     const SHORT_TIMEOUT: Duration = Duration::from_secs(4);
-    const READ_TIMEOUT: Duration = Duration::from_secs(120);
+    // Per attempt, for retried requests
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+    // Allows for retries
+    const SETUP_TIMEOUT: Duration = Duration::from_secs(15);
+    // Per part read
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
 
     let mut comm = router.req(eid);
     let comm = &mut comm;
 
-    // Set a fixed timeout for the first sequence
+    // Set a fixed timeout for the first sequence. Idempotent requests
+    // are retried within that.
     let first_sequence = async {
         // Get PLDM Versions
-        let _ = check_version(comm, PLDM_TYPE_CONTROL, PLDM_BASE_VERSION).await;
-        let _ = check_version(
-            comm,
-            pldm_file::PLDM_TYPE_FILE_TRANSFER,
-            PLDM_FILE_VERSION,
-        )
+        let _ = with_retries(RETRIES, REQUEST_TIMEOUT, async || {
+            check_version(comm, PLDM_TYPE_CONTROL, PLDM_BASE_VERSION).await
+        })
+        .await;
+        let _ = with_retries(RETRIES, REQUEST_TIMEOUT, async || {
+            check_version(
+                comm,
+                pldm_file::PLDM_TYPE_FILE_TRANSFER,
+                PLDM_FILE_VERSION,
+            )
+            .await
+        })
         .await;
 
         // Get PLDM Types
-        let mut buf = [0u8; 10];
-        let types = ctrq::get_pldm_types(comm, &mut buf)
-            .await
-            .inspect_err(|e| warn!("Error from Get PLDM Types: {e}"))?;
+        let types = with_retries(RETRIES, REQUEST_TIMEOUT, async || {
+            let mut buf = [0u8; 10];
+            let types = ctrq::get_pldm_types(comm, &mut buf).await?;
+            // Copied, since the response buffer can't outlive a retry
+            heapless::Vec::<u8, 10>::from_slice(types)
+                .map_err(|_| proto_error!("Too many PLDM types"))
+        })
+        .await
+        .inspect_err(|e| warn!("Error from Get PLDM Types: {e}"))?;
         info!("PLDM types: {types:?}");
         if !(types.contains(&PLDM_TYPE_CONTROL)
             && types.contains(&PLDM_TYPE_FILE_TRANSFER))
@@ -224,32 +352,39 @@ async fn pldm_run_file(
         .await;
 
         // PDR Repository Info
-        let pdr_info = platrq::get_pdr_repository_info(comm)
-            .await
-            .inspect_err(|e| {
-                warn!("Error from Get PDR Repository Info: {e}")
-            })?;
+        let pdr_info = with_retries(RETRIES, REQUEST_TIMEOUT, async || {
+            platrq::get_pdr_repository_info(comm).await
+        })
+        .await
+        .inspect_err(|e| warn!("Error from Get PDR Repository Info: {e}"))?;
 
         info!("PDR Repository Info: {pdr_info:?}");
 
-        // Find a File Descriptor PDR
+        // Collect File Descriptor PDRs from the whole repository
         let mut p = platrq::get_pdr(comm);
-        let mut filedesc = None;
+        let mut files = heapless::Vec::<_, MAX_FILES>::new();
         while let Some(r) = p.next().await {
             match r {
-                Ok(pdr) => {
-                    if let PdrRecord::FileDescriptor(fd) = pdr {
-                        filedesc = Some(fd);
-                        break;
-                    } else {
-                        info!("Skipping non-file PDR type {}", pdr.pdr_type());
+                Ok(PdrRecord::FileDescriptor(fd)) => {
+                    info!(
+                        "File Descriptor PDR, file id {}",
+                        fd.file_identifier
+                    );
+                    if files.push(fd).is_err() {
+                        warn!("Too many File Descriptor PDRs, ignoring");
                     }
                 }
+                Ok(pdr) => {
+                    info!("Skipping non-file PDR type {}", pdr.pdr_type());
+                }
                 Err(e) => info!("Error fetching PDR: {e}, skipping"),
             }
         }
 
-        let Some(filedesc) = filedesc else {
+        // Lowest file identifier is transferred
+        let Some(filedesc) =
+            files.into_iter().min_by_key(|fd| fd.file_identifier)
+        else {
             return Err(proto_error!("No File Descriptor PDR found"));
         };
         info!("PDR: {filedesc:x?}");
@@ -257,6 +392,7 @@ async fn pldm_run_file(
 
         // NegotiateTransferParameters
         let req_types = [pldm_file::PLDM_TYPE_FILE_TRANSFER];
+        let mut buf = [0u8; 10];
         let (size, neg_types) = ctrq::negotiate_transfer_parameters(
             comm,
             &req_types,
@@ -271,10 +407,24 @@ async fn pldm_run_file(
 
     // Whole first sequence runs with one timeout
     let filedesc = first_sequence
-        .with_timeout(SHORT_TIMEOUT)
+        .with_timeout(SETUP_TIMEOUT)
         .await
         .inspect_err(|_| warn!("PLDM file transfer setup timed out"))??;
 
+    let staging_region = flash.lock().await.staging_region();
+    let staging_len = staging_region.len();
+    if staging_region.is_empty() {
+        warn!("No staging area until the boot slot is confirmed");
+        return Err(proto_error!("No staging area"));
+    }
+    if filedesc.file_max_size as usize > staging_len {
+        warn!(
+            "File size {} exceeds staging area {staging_len}",
+            filedesc.file_max_size
+        );
+        return Err(proto_error!("File too large"));
+    }
+
     // File Open
     let id = FileIdentifier(filedesc.file_identifier);
     let attrs = DfOpenAttributes::empty();
@@ -284,38 +434,157 @@ async fn pldm_run_file(
         .inspect_err(|e| warn!("df_open failed {e}"))?;
 
     // File Read
-    info!("Reading entire file ({} bytes)...", filedesc.file_max_size);
+    let file_size = filedesc.file_max_size as usize;
+    let mut from = match *resume {
+        Some(r)
+            if r.file_identifier == filedesc.file_identifier
+                && r.file_size == file_size =>
+        {
+            r.offset
+        }
+        _ => 0,
+    };
     let start = embassy_time::Instant::now();
 
-    let mut hash = hash.lock().await;
-    let mut hash_ctx = hash.start(
-        embassy_stm32::hash::Algorithm::SHA256,
-        embassy_stm32::hash::DataType::Width8,
-        None,
-    );
-    let mut count = 0;
-    df_read_with(
-        comm,
-        fd,
-        0,
-        filedesc.file_max_size as usize,
-        part_buf,
-        |b| {
-            count += b.len();
-            hash.update_blocking(&mut hash_ctx, b);
-            Ok(())
-        },
-    )
-    .with_timeout(READ_TIMEOUT)
-    .await?
-    .inspect_err(|e| warn!("df_read failed {e}"))?;
+    // Flash and the hash engine are only locked while in use, not while
+    // waiting for the responder, so settings and boot confirmation can
+    // still be written during a transfer.
+    let (mut hash_ctx, trailer, crc, count) = loop {
+        if from == 0 {
+            info!("Reading entire file ({file_size} bytes)...");
+            let mut flash = flash.lock().await;
+            flash.erase_staging_records().await.map_err(|e| {
+                warn!("Flash erase failed: {e:?}");
+                proto_error!("Flash erase failed")
+            })?;
+        } else {
+            info!("Resuming file read at {from} of {file_size} bytes...");
+        }
+
+        let mut hash_ctx = hash.lock().await.start(
+            embassy_stm32::hash::Algorithm::SHA256,
+            embassy_stm32::hash::DataType::Width8,
+            None,
+        );
+        let mut trailer = Trailer::new(file_size);
+        // Whole file including any trailer, to compare with crc32(1)
+        let mut crc = Crc32::new();
+
+        // Content staged before an interruption is hashed from flash,
+        // yielding between chunks so other tasks keep running.
+        let mut pos = 0;
+        while pos < from {
+            let chunk = &mut part_buf[..PART_SIZE.min(from - pos)];
+            flash
+                .lock()
+                .await
+                .read(staging_region.start + pos as u32, chunk)
+                .map_err(|e| {
+                    warn!("Flash read failed at {pos}: {e:?}");
+                    proto_error!("Flash read failed")
+                })?;
+            let body = trailer.split(pos, chunk)?;
+            hash.lock().await.update_blocking(&mut hash_ctx, body);
+            crc.update(chunk);
+            pos += chunk.len();
+            yield_now().await;
+        }
+
+        let mut staging = RegionWriter::resume(staging_region.clone(), from);
+        // Read a part at a time, so that flash is erased and programmed
+        // between reads without blocking the executor.
+        let r = async {
+            while staging.written() < file_size {
+                let pos = staging.written();
+                let len = PART_SIZE.min(file_size - pos);
+                let mut pending = 0;
+                df_read_with(comm, fd, pos, len, part_buf, |b| {
+                    write_buf
+                        .get_mut(pending..pending + b.len())
+                        .ok_or_else(|| proto_error!("Part exceeds size"))?
+                        .copy_from_slice(b);
+                    pending += b.len();
+                    Ok(())
+                })
+                .with_timeout(READ_TIMEOUT)
+                .await??;
+
+                let data = &write_buf[..pending];
+                // Trailer is held back from the hash until it is
+                // known whether it is a checksum.
+                let body = trailer.split(pos, data)?;
+                hash.lock().await.update_blocking(&mut hash_ctx, body);
+                crc.update(data);
+                staging
+                    .write(&mut *flash.lock().await, data)
+                    .await
+                    .map_err(|e| {
+                        warn!("Flash write failed at {pos}: {e:?}");
+                        proto_error!("Flash write failed")
+                    })?;
+                *resume = Some(Resume {
+                    file_identifier: filedesc.file_identifier,
+                    file_size,
+                    offset: staging.written(),
+                });
+                if pending < len {
+                    // End of file
+                    break;
+                }
+            }
+            Ok::<_, PldmError>(())
+        }
+        .await;
+
+        let count = staging.written();
+        match r {
+            Ok(_) => break (hash_ctx, trailer, crc, count),
+            Err(e) if from > 0 && count == from => {
+                // Responder may not support reading from an offset
+                warn!("df_read from {from} failed {e}, restarting");
+                *resume = None;
+                from = 0;
+            }
+            Err(e) => {
+                warn!("df_read failed {e}");
+                return Err(e);
+            }
+        }
+    };
+    // Complete, or restarted after a checksum mismatch
+    *resume = None;
 
     let time = start.elapsed().as_millis() as usize;
-    let kbyte_rate = count.checked_div(time).unwrap_or(0);
+    // Resumed content isn't included in the rate
+    let kbyte_rate = (count - from).checked_div(time).unwrap_or(0);
+    let expect = trailer.checksum();
     let mut digest = [0u8; 32];
-    hash.finish_blocking(hash_ctx, &mut digest);
-    info!("Transfer complete. total {count} bytes, {time} ms, {kbyte_rate} kB/s, sha256 {}",
-        Hex(&digest));
+    {
+        let mut hash = hash.lock().await;
+        if expect.is_none() {
+            // Trailer is part of the file content
+            hash.update_blocking(&mut hash_ctx, trailer.data());
+        }
+        hash.finish_blocking(hash_ctx, &mut digest);
+    }
+    info!("Transfer complete. total {count} bytes, {time} ms, {kbyte_rate} kB/s, sha256 {}, crc32 {:08x}",
+        Hex(&digest), crc.finalize());
+    info!("Stored to flash at {:#x}", staging_region.start);
+
+    let checksum_ok = match expect {
+        Some(e) if e == digest => {
+            info!("File checksum matches");
+            true
+        }
+        Some(e) => {
+            warn!("File checksum mismatch, expected sha256 {}", Hex(e));
+            false
+        }
+        None => {
+            info!("No checksum trailer, file not verified");
+            true
+        }
+    };
 
     // File Close
     let attrs = DfCloseAttributes::empty();
@@ -324,5 +593,65 @@ async fn pldm_run_file(
         .await?
         .inspect_err(|e| warn!("df_close failed {e}"))?;
 
+    if !checksum_ok {
+        return Err(proto_error!("File checksum mismatch"));
+    }
     Ok(())
 }
+
+/// Optional checksum trailer at the end of a transferred file.
+///
+/// The trailer is `Trailer::MAGIC` followed by the SHA-256 of the
+/// preceding file content. Files without a trailer are not verified.
+struct Trailer {
+    /// File offset of the trailer
+    start: usize,
+    buf: [u8; Self::LEN],
+    len: usize,
+}
+
+impl Trailer {
+    const MAGIC: [u8; 16] = *b"usbnvme-sha256\0\0";
+    const LEN: usize = Self::MAGIC.len() + 32;
+
+    fn new(file_size: usize) -> Self {
+        Self {
+            start: file_size.saturating_sub(Self::LEN),
+            buf: [0; Self::LEN],
+            len: 0,
+        }
+    }
+
+    /// Stores any part of `data` at `offset` that is within the trailer.
+    ///
+    /// Returns the part of `data` preceding the trailer.
+    fn split<'d>(
+        &mut self,
+        offset: usize,
+        data: &'d [u8],
+    ) -> Result<&'d [u8], PldmError> {
+        let (body, tail) =
+            data.split_at(self.start.saturating_sub(offset).min(data.len()));
+        if !tail.is_empty() {
+            let t = offset + body.len() - self.start;
+            self.buf
+                .get_mut(t..t + tail.len())
+                .ok_or_else(|| proto_error!("File exceeds size"))?
+                .copy_from_slice(tail);
+            self.len = t + tail.len();
+        }
+        Ok(body)
+    }
+
+    /// Received trailer bytes
+    fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns the expected checksum, if a trailer is present.
+    fn checksum(&self) -> Option<&[u8]> {
+        let d = self.data();
+        (d.len() == Self::LEN && d.starts_with(&Self::MAGIC))
+            .then(|| &d[Self::MAGIC.len()..])
+    }
+}
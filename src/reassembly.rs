@@ -0,0 +1,147 @@
+//! Observes incoming fragmented MCTP messages, reporting any that never
+//! complete.
+//!
+//! The MCTP stack discards incomplete messages silently after its
+//! reassembly timeout. Packets are also tracked here as they arrive from
+//! USB, so that lost fragments are logged with the source EID and tag,
+//! and counted.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use mctp::Eid;
+
+pub static REASSEMBLY: Reassembly = Reassembly::new();
+
+/// Matches the mctp-estack reassembly expiry.
+const EXPIRY: Duration = Duration::from_millis(6000);
+
+/// Incomplete messages tracked at once. Further messages are not
+/// tracked until one completes or expires.
+const MAX_PARTIAL: usize = 8;
+
+const FLAG_SOM: u8 = 1 << 7;
+const FLAG_EOM: u8 = 1 << 6;
+/// Tag owner and message tag
+const FLAG_TAG_MASK: u8 = 0x0f;
+
+/// A message with a start packet but no end packet yet.
+struct Partial {
+    source: Eid,
+    /// Tag owner and message tag
+    tag: u8,
+    /// Time of the most recent packet
+    last: Instant,
+}
+
+pub struct Reassembly {
+    partial: BlockingMutex<
+        CriticalSectionRawMutex,
+        RefCell<Vec<Partial, MAX_PARTIAL>>,
+    >,
+    /// Total incomplete messages expired
+    expired: AtomicU32,
+}
+
+impl Reassembly {
+    const fn new() -> Self {
+        Self {
+            partial: BlockingMutex::new(RefCell::new(Vec::new())),
+            expired: AtomicU32::new(0),
+        }
+    }
+
+    /// Records an incoming MCTP packet, including the MCTP header.
+    pub fn packet(&self, pkt: &[u8]) {
+        let &[_ver, _dest, source, flags, ..] = pkt else {
+            return;
+        };
+        if flags & FLAG_SOM != 0 && flags & FLAG_EOM != 0 {
+            // Single packet message
+            return;
+        }
+        let source = Eid(source);
+        let tag = flags & FLAG_TAG_MASK;
+        let now = Instant::now();
+
+        let restarted = self.partial.lock(|p| {
+            let mut p = p.borrow_mut();
+            let pos = p.iter().position(|m| m.source == source && m.tag == tag);
+
+            if flags & FLAG_SOM != 0 {
+                let restarted = pos.map(|i| p.swap_remove(i)).is_some();
+                let _ = p.push(Partial {
+                    source,
+                    tag,
+                    last: now,
+                });
+                restarted
+            } else {
+                if let Some(i) = pos {
+                    if flags & FLAG_EOM != 0 {
+                        p.swap_remove(i);
+                    } else {
+                        p[i].last = now;
+                    }
+                }
+                false
+            }
+        });
+
+        if restarted {
+            self.expire_one(source, tag, "restarted");
+        }
+    }
+
+    /// Expires messages with no packets within the reassembly timeout.
+    ///
+    /// Returns the number expired.
+    pub fn expire(&self) -> u32 {
+        let now = Instant::now();
+        let mut expired = Vec::<_, MAX_PARTIAL>::new();
+        self.partial.lock(|p| {
+            p.borrow_mut().retain(|m| {
+                if now.saturating_duration_since(m.last) < EXPIRY {
+                    return true;
+                }
+                // Capacity matches
+                let _ = expired.push((m.source, m.tag));
+                false
+            })
+        });
+
+        for &(source, tag) in &expired {
+            self.expire_one(source, tag, "timed out");
+        }
+        expired.len() as u32
+    }
+
+    /// Forgets incomplete messages, which can't complete after USB
+    /// disconnects.
+    pub fn clear(&self) {
+        self.partial.lock(|p| p.borrow_mut().clear())
+    }
+
+    /// Total incomplete messages expired since boot.
+    pub fn expired(&self) -> u32 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    fn expire_one(&self, source: Eid, tag: u8, why: &str) {
+        let total = self.expired.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "Incomplete MCTP message from eid {source} tag {tag:#x} {why}, {total} total"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! MCTP control routing commands.
+//!
+//! Resolve Endpoint ID and Get Routing Table Entries, so that a bus
+//! owner's topology scan can query the device. All EIDs other than
+//! our own are routed out the USB port, towards the bus owner. Peers on
+//! the optional SMBus port aren't reported.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use mctp::Eid;
+
+use crate::Routes;
+
+const RQ: u8 = 1 << 7;
+
+const CONTROL_RESOLVE_EID: u8 = 0x07;
+const CONTROL_GET_ROUTING_TABLE: u8 = 0x0a;
+
+const CC_SUCCESS: u8 = 0x00;
+const CC_ERROR_INVALID_DATA: u8 = 0x02;
+const CC_ERROR_INVALID_LENGTH: u8 = 0x03;
+
+/// DSP0239 physical transport binding for MCTP over USB
+const TRANSPORT_USB: u8 = 0x03;
+/// DSP0239 physical medium, USB 2.0 compatible
+const MEDIUM_USB2: u8 = 0x11;
+
+/// Entry handle reported when no further entries remain
+const NO_MORE_ENTRIES: u8 = 0xff;
+
+/// Routing table entry type for a single non-bridge endpoint, in bits 7:6
+const ENTRY_SINGLE_ENDPOINT: u8 = 0b00 << 6;
+
+/// Length of a routing table entry, with no physical address
+const ENTRY_LEN: usize = 6;
+
+/// Response length, including the control message header
+pub const ROUTING_RESP_LEN: usize = 5 + ENTRY_LEN;
+
+/// Handles Resolve Endpoint ID and Get Routing Table Entries requests.
+///
+/// `bus_owner` is the EID that last assigned our EID, if any.
+/// Returns `None` if `msg` is not one of those requests, to be handled
+/// elsewhere.
+pub fn control_routing<'a>(
+    msg: &[u8],
+    bus_owner: Option<Eid>,
+    rsp: &'a mut [u8; ROUTING_RESP_LEN],
+) -> Option<&'a [u8]> {
+    let [hdr, cmd, body @ ..] = msg else {
+        return None;
+    };
+    if hdr & RQ == 0 {
+        return None;
+    }
+    rsp[0] = hdr & !RQ;
+    rsp[1] = *cmd;
+
+    let len = match *cmd {
+        CONTROL_RESOLVE_EID => resolve_eid(body, bus_owner, &mut rsp[2..]),
+        CONTROL_GET_ROUTING_TABLE => {
+            routing_table(body, bus_owner, &mut rsp[2..])
+        }
+        _ => return None,
+    };
+    Some(&rsp[..2 + len])
+}
+
+/// Writes a Resolve Endpoint ID response body, returning the length.
+///
+/// The bus owner is the next hop for any other EID. MCTP over USB is
+/// point to point, so no physical address is reported.
+fn resolve_eid(body: &[u8], bus_owner: Option<Eid>, rsp: &mut [u8]) -> usize {
+    let &[target] = body else {
+        rsp[0] = CC_ERROR_INVALID_LENGTH;
+        return 1;
+    };
+    let (Ok(target), Some(bus_owner)) = (Eid::new_normal(target), bus_owner)
+    else {
+        debug!("Resolve EID {target} not routable");
+        rsp[0] = CC_ERROR_INVALID_DATA;
+        return 1;
+    };
+
+    debug!("Resolve EID {target} via {bus_owner}");
+    rsp[0] = CC_SUCCESS;
+    rsp[1] = bus_owner.0;
+    2
+}
+
+/// Writes a Get Routing Table Entries response body, returning the
+/// length.
+///
+/// The table has a single entry for the bus owner on the USB port,
+/// once an EID has been assigned. Entries fit in one response, so only
+/// entry handle 0 is valid.
+fn routing_table(body: &[u8], bus_owner: Option<Eid>, rsp: &mut [u8]) -> usize {
+    let &[handle] = body else {
+        rsp[0] = CC_ERROR_INVALID_LENGTH;
+        return 1;
+    };
+    if handle != 0 {
+        rsp[0] = CC_ERROR_INVALID_DATA;
+        return 1;
+    }
+
+    rsp[0] = CC_SUCCESS;
+    rsp[1] = NO_MORE_ENTRIES;
+    let Some(bus_owner) = bus_owner else {
+        rsp[2] = 0;
+        return 3;
+    };
+    rsp[2] = 1;
+
+    let entry = &mut rsp[3..3 + ENTRY_LEN];
+    // EID range size
+    entry[0] = 1;
+    entry[1] = bus_owner.0;
+    // Dynamic entry, learned from Set Endpoint ID
+    entry[2] = ENTRY_SINGLE_ENDPOINT | Routes::USB_INDEX.0 as u8;
+    entry[3] = TRANSPORT_USB;
+    entry[4] = MEDIUM_USB2;
+    // Physical address size
+    entry[5] = 0;
+    3 + ENTRY_LEN
+}
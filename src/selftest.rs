@@ -0,0 +1,93 @@
+//! Power-on self-test of MCTP over USB framing.
+//!
+//! Packets are framed into a USB transfer as `Sender` does, then decoded
+//! as `Receiver` does, checking that they round trip. Needs no host, so
+//! a broken framing build or packet sizing is caught on the bench.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use heapless::Vec;
+use mctp_estack::usb::MctpUsbHandler;
+use mctp_usb_embassy::MCTP_USB_MAX_PACKET;
+
+use crate::{MCTP_BASELINE_MTU, MCTP_HEADER, MCTP_USB_HEADER, USB_MTU};
+
+/// Packets in the test transfer, including the MCTP header.
+const PACKET_LENS: [usize; 3] =
+    [USB_MTU, MCTP_BASELINE_MTU + MCTP_HEADER, MCTP_HEADER + 1];
+
+const _: () = assert!(USB_MTU + MCTP_USB_HEADER <= MCTP_USB_MAX_PACKET);
+
+/// Runs the self-test, logging the result.
+///
+/// Returns whether it passed.
+pub fn run() -> bool {
+    match check() {
+        Ok(()) => {
+            info!("USB framing self-test passed");
+            true
+        }
+        Err(e) => {
+            error!("USB framing self-test failed: {e}");
+            false
+        }
+    }
+}
+
+/// Fills a test packet, distinct for each index `i`.
+fn fill(i: usize, pkt: &mut [u8]) {
+    // Version 1, destination and source EID, SOM | EOM | TO with tag `i`
+    pkt[..MCTP_HEADER].copy_from_slice(&[0x01, 0x08, 0x09, 0xc8 | i as u8]);
+    for (j, b) in pkt[MCTP_HEADER..].iter_mut().enumerate() {
+        *b = (i * 0x40 + j) as u8;
+    }
+}
+
+fn check() -> Result<(), &'static str> {
+    let mut pkt = [0u8; USB_MTU];
+    let mut hdr = [0u8; MCTP_USB_HEADER];
+
+    // Frame all packets in one transfer, as Sender::feed()
+    let mut xfer = Vec::<u8, MCTP_USB_MAX_PACKET>::new();
+    for (i, &len) in PACKET_LENS.iter().enumerate() {
+        fill(i, &mut pkt[..len]);
+        MctpUsbHandler::header(len, &mut hdr).map_err(|_| "bad header")?;
+        xfer.extend_from_slice(&hdr)
+            .map_err(|_| "transfer too long")?;
+        xfer.extend_from_slice(&pkt[..len])
+            .map_err(|_| "transfer too long")?;
+    }
+
+    // Decode, as Receiver::receive()
+    let mut rem = xfer.as_slice();
+    for (i, &len) in PACKET_LENS.iter().enumerate() {
+        let (p, r) =
+            MctpUsbHandler::decode(rem).map_err(|_| "decode failed")?;
+        fill(i, &mut pkt[..len]);
+        if p != &pkt[..len] {
+            debug!("Packet {i} len {} expected {len}", p.len());
+            return Err("packet mismatch");
+        }
+        rem = r;
+    }
+    if !rem.is_empty() {
+        return Err("trailing data");
+    }
+
+    // The length byte must not wrap
+    if MctpUsbHandler::header(u8::MAX as usize, &mut hdr).is_ok() {
+        return Err("oversized packet framed");
+    }
+
+    // Wrong DMTF ID
+    xfer[0] ^= 0xff;
+    if MctpUsbHandler::decode(&xfer).is_ok() {
+        return Err("bad header decoded");
+    }
+    Ok(())
+}
@@ -0,0 +1,455 @@
+//! Persistent device settings.
+//!
+//! Settings are stored in a reserved sector of the external XSPI flash.
+//! Provisioned records are read from another sector, never written here.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use deku::prelude::*;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
+use mctp::Eid;
+
+use crate::flash::{self, Flash, FlashError};
+use crate::{SharedFlash, SignalCS};
+
+/// Time that settings must be unchanged before writing to flash.
+///
+/// Avoids flash wear from repeated Set Endpoint ID.
+const STORE_DELAY: Duration = Duration::from_secs(10);
+
+/// Maximum length of a stored USB string, in bytes
+pub const USB_STRING_LEN: usize = 32;
+
+/// Offset of the USB identity record within `PROVISIONED_REGION`
+const USB_RECORD_OFFSET: u32 = 0x100;
+
+/// Offset of the NVMe profile record within `PROVISIONED_REGION`
+const NVME_RECORD_OFFSET: u32 = 0x200;
+
+/// Offset of the vendor allowlist provisioned marker within
+/// `PROVISIONED_REGION`
+const VENDOR_MARKER_OFFSET: u32 = 0x000;
+
+/// Offset of the vendor command allowlist record within
+/// `PROVISIONED_REGION`
+const VENDOR_RECORD_OFFSET: u32 = 0x300;
+
+/// Provisioned marker, "VCPR". Once present, an invalid or missing
+/// allowlist record permits no EID.
+const VENDOR_PROVISIONED_MAGIC: u32 = 0x5250_4356;
+
+/// Offset of the NVMe topology record within `PROVISIONED_REGION`
+const TOPOLOGY_RECORD_OFFSET: u32 = 0x400;
+
+/// Maximum namespaces in an NVMe topology record
+pub const MAX_TOPOLOGY_NAMESPACES: usize = 4;
+
+/// Maximum EIDs in the vendor command allowlist
+pub const MAX_VENDOR_EIDS: usize = 8;
+
+/// EIDs permitted to issue privileged vendor commands.
+pub type VendorAllowList = Vec<Eid, MAX_VENDOR_EIDS>;
+
+// On-flash format
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct SettingsRecord {
+    magic: u32,
+    version: u8,
+    eid: u8,
+}
+
+// On-flash format. Strings are UTF-8, padded with zero bytes.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct UsbRecord {
+    magic: u32,
+    version: u8,
+    vid: u16,
+    pid: u16,
+    manufacturer: [u8; USB_STRING_LEN],
+    product: [u8; USB_STRING_LEN],
+}
+
+// On-flash format
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct NvmeRecord {
+    magic: u32,
+    version: u8,
+    profile: u8,
+}
+
+impl NvmeRecord {
+    const MAGIC: u32 = 0x504d_564e;
+    const VERSION: u8 = 1;
+    const LEN: usize = 6;
+}
+
+// On-flash format
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct TopologyNamespace {
+    /// Capacity in 512 byte blocks
+    blocks: u64,
+    /// Bitmask of attached controllers
+    attached: u8,
+}
+
+// On-flash format. Namespaces past `namespace_count` are ignored.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct TopologyRecord {
+    magic: u32,
+    version: u8,
+    /// 0xffff for the build environment default
+    pci_sdid: u16,
+    controllers: u8,
+    namespace_count: u8,
+    namespaces: [TopologyNamespace; MAX_TOPOLOGY_NAMESPACES],
+}
+
+impl TopologyRecord {
+    const MAGIC: u32 = 0x544d_564e;
+    const VERSION: u8 = 1;
+    const LEN: usize = 9 + 9 * MAX_TOPOLOGY_NAMESPACES;
+    const SDID_DEFAULT: u16 = 0xffff;
+}
+
+// On-flash format. EIDs past `count` are ignored.
+#[derive(DekuRead, DekuWrite, Debug)]
+#[deku(endian = "little")]
+struct VendorRecord {
+    magic: u32,
+    version: u8,
+    count: u8,
+    eids: [u8; MAX_VENDOR_EIDS],
+}
+
+impl VendorRecord {
+    const MAGIC: u32 = 0x4c41_4356;
+    const VERSION: u8 = 1;
+    const LEN: usize = 6 + MAX_VENDOR_EIDS;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Most recently assigned EID. `Eid(0)` if none.
+    pub eid: Eid,
+    /// USB identity, replacing the defaults. Only set by provisioning.
+    pub usb: Option<UsbIdentity>,
+    /// Index into `nvmeconfig::PROFILES`. Only set by provisioning.
+    #[cfg_attr(not(feature = "nvme-mi"), allow(unused))]
+    pub nvme_profile: Option<u8>,
+    /// NVMe topology, used in place of `nvme_profile`. Only set by
+    /// provisioning.
+    #[cfg_attr(not(feature = "nvme-mi"), allow(unused))]
+    pub nvme_topology: Option<NvmeTopologySettings>,
+    /// EIDs permitted to issue privileged vendor commands. `None` permits
+    /// any EID, if no allowlist was provisioned. Only set by provisioning.
+    pub vendor_allow: Option<VendorAllowList>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            eid: Eid(0),
+            usb: None,
+            nvme_profile: None,
+            nvme_topology: None,
+            vendor_allow: None,
+        }
+    }
+}
+
+/// NVMe-MI subsystem topology, for emulating models not in
+/// `nvmeconfig::PROFILES`.
+///
+/// Limits are checked when the subsystem is built.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NvmeTopologySettings {
+    /// `None` for the build environment `NVME_MI_DEV_PCI_SDID`
+    pub pci_sdid: Option<u16>,
+    pub controllers: u8,
+    /// Capacity in 512 byte blocks, and a bitmask of attached
+    /// controllers
+    pub namespaces: Vec<(u64, u8), MAX_TOPOLOGY_NAMESPACES>,
+}
+
+impl NvmeTopologySettings {
+    /// Reads the topology from flash.
+    ///
+    /// Returns `None` for blank flash or an invalid record.
+    fn load(flash: &mut Flash) -> Option<Self> {
+        let mut buf = [0u8; TopologyRecord::LEN];
+        let addr = flash::PROVISIONED_REGION.start + TOPOLOGY_RECORD_OFFSET;
+        if let Err(e) = flash.read(addr, &mut buf) {
+            warn!("Failed reading NVMe topology: {e:?}");
+            return None;
+        }
+
+        let (_, rec) = TopologyRecord::from_bytes((&buf, 0)).ok()?;
+        if rec.magic != TopologyRecord::MAGIC
+            || rec.version != TopologyRecord::VERSION
+        {
+            // Includes blank 0xff flash
+            return None;
+        }
+
+        let Some(namespaces) =
+            rec.namespaces.get(..rec.namespace_count as usize)
+        else {
+            warn!(
+                "Ignoring NVMe topology with {} namespaces",
+                rec.namespace_count
+            );
+            return None;
+        };
+        Some(Self {
+            pci_sdid: (rec.pci_sdid != TopologyRecord::SDID_DEFAULT)
+                .then_some(rec.pci_sdid),
+            controllers: rec.controllers,
+            // Capacity matches the record
+            namespaces: namespaces
+                .iter()
+                .map(|n| (n.blocks, n.attached))
+                .collect(),
+        })
+    }
+}
+
+/// USB device identity, for rebranded devices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    /// Empty to use the default
+    pub manufacturer: String<USB_STRING_LEN>,
+    /// Empty to use the default
+    pub product: String<USB_STRING_LEN>,
+}
+
+impl UsbIdentity {
+    const MAGIC: u32 = 0x4942_5355;
+    const VERSION: u8 = 1;
+    const RECORD_LEN: usize = 9 + 2 * USB_STRING_LEN;
+
+    /// Reads the USB identity from flash.
+    ///
+    /// Returns `None` for blank flash or an invalid record.
+    fn load(flash: &mut Flash) -> Option<Self> {
+        let mut buf = [0u8; Self::RECORD_LEN];
+        let addr = flash::PROVISIONED_REGION.start + USB_RECORD_OFFSET;
+        if let Err(e) = flash.read(addr, &mut buf) {
+            warn!("Failed reading USB settings: {e:?}");
+            return None;
+        }
+
+        let (_, rec) = UsbRecord::from_bytes((&buf, 0)).ok()?;
+        if rec.magic != Self::MAGIC || rec.version != Self::VERSION {
+            // Includes blank 0xff flash
+            return None;
+        }
+
+        let (Some(manufacturer), Some(product)) = (
+            Self::parse_string(&rec.manufacturer),
+            Self::parse_string(&rec.product),
+        ) else {
+            warn!("Ignoring USB settings with invalid strings");
+            return None;
+        };
+
+        Some(Self {
+            vid: rec.vid,
+            pid: rec.pid,
+            manufacturer,
+            product,
+        })
+    }
+
+    /// Returns a zero padded UTF-8 string, or `None` if invalid.
+    fn parse_string(
+        b: &[u8; USB_STRING_LEN],
+    ) -> Option<String<USB_STRING_LEN>> {
+        let l = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+        if b[l..].iter().any(|&c| c != 0) {
+            return None;
+        }
+        let s = core::str::from_utf8(&b[..l]).ok()?;
+        String::try_from(s).ok()
+    }
+}
+
+impl Settings {
+    const MAGIC: u32 = 0x5345_4e55;
+    const VERSION: u8 = 1;
+    const RECORD_LEN: usize = 6;
+
+    /// Reads settings from flash.
+    ///
+    /// Defaults are returned for blank flash or an invalid record.
+    pub fn load(flash: &mut Flash) -> Self {
+        Self {
+            eid: Self::load_eid(flash),
+            usb: UsbIdentity::load(flash),
+            nvme_profile: Self::load_nvme_profile(flash),
+            nvme_topology: NvmeTopologySettings::load(flash),
+            vendor_allow: Self::load_vendor_allow(flash),
+        }
+    }
+
+    /// Reads the vendor command allowlist.
+    ///
+    /// Fails closed, permitting no EID, if the record is invalid and
+    /// either the provisioned marker is present or the record isn't
+    /// blank. `None` is only returned if nothing was provisioned.
+    fn load_vendor_allow(flash: &mut Flash) -> Option<VendorAllowList> {
+        let mut marker = [0u8; 4];
+        let mut buf = [0u8; VendorRecord::LEN];
+        let base = flash::PROVISIONED_REGION.start;
+        let r = flash
+            .read(base + VENDOR_MARKER_OFFSET, &mut marker)
+            .and_then(|()| flash.read(base + VENDOR_RECORD_OFFSET, &mut buf));
+        if let Err(e) = r {
+            warn!("Failed reading vendor allowlist: {e:?}, permitting no EID");
+            return Some(Vec::new());
+        }
+
+        let provisioned =
+            u32::from_le_bytes(marker) == VENDOR_PROVISIONED_MAGIC;
+        match Self::parse_vendor_allow(&buf) {
+            Some(allow) => Some(allow),
+            None if provisioned || buf.iter().any(|&b| b != 0xff) => {
+                warn!("Invalid vendor allowlist, permitting no EID");
+                Some(Vec::new())
+            }
+            // Never provisioned
+            None => None,
+        }
+    }
+
+    /// Returns the allowlist in a record, or `None` if invalid.
+    fn parse_vendor_allow(buf: &[u8]) -> Option<VendorAllowList> {
+        let (_, rec) = VendorRecord::from_bytes((buf, 0)).ok()?;
+        if rec.magic != VendorRecord::MAGIC
+            || rec.version != VendorRecord::VERSION
+        {
+            // Includes blank 0xff flash
+            return None;
+        }
+
+        let Some(eids) = rec.eids.get(..rec.count as usize) else {
+            warn!("Vendor allowlist count {} too large", rec.count);
+            return None;
+        };
+        let mut allow = Vec::new();
+        for &eid in eids {
+            let Ok(eid) = Eid::new_normal(eid) else {
+                warn!("Vendor allowlist has invalid EID {eid}");
+                return None;
+            };
+            // Capacity matches the record
+            let _ = allow.push(eid);
+        }
+        Some(allow)
+    }
+
+    fn load_nvme_profile(flash: &mut Flash) -> Option<u8> {
+        let mut buf = [0u8; NvmeRecord::LEN];
+        let addr = flash::PROVISIONED_REGION.start + NVME_RECORD_OFFSET;
+        if let Err(e) = flash.read(addr, &mut buf) {
+            warn!("Failed reading NVMe settings: {e:?}");
+            return None;
+        }
+
+        let (_, rec) = NvmeRecord::from_bytes((&buf, 0)).ok()?;
+        // Includes blank 0xff flash
+        (rec.magic == NvmeRecord::MAGIC && rec.version == NvmeRecord::VERSION)
+            .then_some(rec.profile)
+    }
+
+    fn load_eid(flash: &mut Flash) -> Eid {
+        let mut buf = [0u8; Self::RECORD_LEN];
+        if let Err(e) = flash.read(flash::SETTINGS_REGION.start, &mut buf) {
+            warn!("Failed reading settings: {e:?}");
+            return Eid(0);
+        }
+
+        let Ok((_, rec)) = SettingsRecord::from_bytes((&buf, 0)) else {
+            return Eid(0);
+        };
+
+        if rec.magic != Self::MAGIC || rec.version != Self::VERSION {
+            // Includes blank 0xff flash
+            debug!("No stored settings");
+            return Eid(0);
+        }
+
+        Eid::new_normal(rec.eid).unwrap_or_else(|_| {
+            warn!("Ignoring invalid stored EID {}", rec.eid);
+            Eid(0)
+        })
+    }
+
+    /// Writes settings to flash.
+    pub async fn store(&self, flash: &mut Flash) -> Result<(), FlashError> {
+        let rec = SettingsRecord {
+            magic: Self::MAGIC,
+            version: Self::VERSION,
+            eid: self.eid.0,
+        };
+        let mut buf = [0u8; Self::RECORD_LEN];
+        let l = rec.to_slice(&mut buf).unwrap();
+        debug_assert_eq!(l, buf.len());
+
+        flash.sector_erase(flash::SETTINGS_REGION.start).await?;
+        flash.program(flash::SETTINGS_REGION.start, &buf).await
+    }
+}
+
+/// Task to write updated settings to flash.
+///
+/// Writes occur once updates have settled for `STORE_DELAY`.
+#[embassy_executor::task]
+pub(crate) async fn settings_task(
+    flash: &'static SharedFlash,
+    initial: Settings,
+    eid_update: &'static SignalCS<Eid>,
+) -> ! {
+    let mut stored = initial;
+    loop {
+        let mut eid = eid_update.wait().await;
+
+        // Wait for updates to settle
+        while let Either::First(e) =
+            select(eid_update.wait(), Timer::after(STORE_DELAY)).await
+        {
+            eid = e;
+        }
+
+        let new = Settings {
+            eid,
+            ..stored.clone()
+        };
+        if new == stored {
+            debug!("Settings unchanged");
+            continue;
+        }
+
+        let mut flash = flash.lock().await;
+        match new.store(&mut flash).await {
+            Ok(()) => {
+                info!("Stored settings {new:?}");
+                stored = new;
+            }
+            Err(e) => warn!("Failed storing settings: {e:?}"),
+        }
+    }
+}
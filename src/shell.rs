@@ -0,0 +1,299 @@
+//! Command shell on the USB serial interface.
+//!
+//! Lines received on the CDC-ACM interface are run as commands, for
+//! bring-up without host MCTP tooling. Replies are queued with the serial
+//! log, regardless of log levels. Input isn't echoed, so a terminal needs
+//! local echo.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::fmt::Arguments;
+use core::str::FromStr;
+
+use embassy_stm32::peripherals::USB_OTG_HS;
+use embassy_stm32::usb::Driver;
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::cdc_acm;
+use heapless::String;
+use log::LevelFilter;
+#[cfg(feature = "mctp-bench")]
+use mctp::Eid;
+use mctp_estack::Router;
+
+use crate::ccvendor::BenchControl;
+#[cfg(feature = "mctp-bench")]
+use crate::ccvendor::{BenchPattern, BenchRequest, MctpBench};
+use crate::multilog::{self, MultiLog};
+use crate::Routes;
+
+type UsbSerialReceiver =
+    cdc_acm::Receiver<'static, Driver<'static, USB_OTG_HS>>;
+
+/// Longest command line, longer lines are rejected.
+const MAX_LINE: usize = 80;
+
+/// Time for a reply to reach the serial port before rebooting.
+const REBOOT_DELAY: Duration = Duration::from_millis(100);
+
+/// Default `bench` message length.
+#[cfg(feature = "mctp-bench")]
+const BENCH_DEFAULT_LEN: usize = 100;
+
+/// Commands and their descriptions
+const HELP: &[(&str, &str)] = &[
+    ("help", "this message"),
+    ("eid", "own EID"),
+    ("routes", "MCTP routes"),
+    #[cfg(feature = "mctp-bench")]
+    ("bench <eid> <count> [len]", "send mctp-bench messages"),
+    ("log", "log levels"),
+    ("log <level>", "set the runtime log level"),
+    (
+        "log <module> <level|none>",
+        "set a module level, @serial for serial",
+    ),
+    ("reboot [loader]", "reset, optionally staying in xspiloader"),
+];
+
+struct Shell {
+    router: &'static Router<'static>,
+    routes: &'static Routes,
+    #[cfg_attr(not(feature = "mctp-bench"), allow(unused))]
+    bench_control: &'static BenchControl,
+    logger: &'static MultiLog,
+}
+
+/// Reads command lines from the USB serial interface and runs them.
+#[embassy_executor::task]
+pub async fn shell_task(
+    mut rx: UsbSerialReceiver,
+    router: &'static Router<'static>,
+    routes: &'static Routes,
+    bench_control: &'static BenchControl,
+    logger: &'static MultiLog,
+) -> ! {
+    let shell = Shell {
+        router,
+        routes,
+        bench_control,
+        logger,
+    };
+    let mut buf = [0u8; 64];
+
+    loop {
+        rx.wait_connection().await;
+        let mut line = String::<MAX_LINE>::new();
+        // The current line didn't fit
+        let mut overflow = false;
+
+        // Until disconnected
+        while let Ok(n) = rx.read_packet(&mut buf).await {
+            for &b in &buf[..n] {
+                match b {
+                    b'\r' | b'\n' => {
+                        if overflow {
+                            shell.print(format_args!("Line too long")).await;
+                        } else {
+                            shell.run(line.trim()).await;
+                        }
+                        line.clear();
+                        overflow = false;
+                    }
+                    // Backspace or delete
+                    0x08 | 0x7f => {
+                        line.pop();
+                    }
+                    b' '..=b'~' => {
+                        if line.push(b as char).is_err() {
+                            overflow = true;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+impl Shell {
+    async fn print(&self, args: Arguments<'_>) {
+        self.logger.serial_print(args).await
+    }
+
+    /// Runs a command line.
+    async fn run(&self, line: &str) {
+        let mut args = line.split_ascii_whitespace();
+        let Some(cmd) = args.next() else {
+            return;
+        };
+        debug!("shell: {line}");
+
+        match cmd {
+            "help" => {
+                for (c, desc) in HELP {
+                    self.print(format_args!("{c:<26} {desc}")).await;
+                }
+            }
+            "eid" => {
+                let eid = self.router.get_eid().await;
+                self.print(format_args!("eid {eid}")).await;
+            }
+            "routes" => self.routes().await,
+            #[cfg(feature = "mctp-bench")]
+            "bench" => self.bench(args).await,
+            "log" => self.log(args).await,
+            "reboot" => {
+                let to_loader = match args.next() {
+                    None => false,
+                    Some("loader") => true,
+                    Some(a) => {
+                        self.print(format_args!("Unknown reboot {a}")).await;
+                        return;
+                    }
+                };
+                info!("Rebooting from shell");
+                self.print(format_args!("Rebooting")).await;
+                Timer::after(REBOOT_DELAY).await;
+                if to_loader {
+                    crate::stmutil::reboot_to_loader();
+                }
+                crate::stmutil::reboot();
+            }
+            _ => {
+                self.print(format_args!("Unknown command {cmd}, try help"))
+                    .await
+            }
+        }
+    }
+
+    /// Prints routes in the order `Routes::by_eid()` applies them.
+    async fn routes(&self) {
+        let eid = self.router.get_eid().await;
+        self.print(format_args!("eid {eid} local")).await;
+        #[cfg(feature = "nvme-mi-smbus")]
+        for (e, addr) in crate::smbus::NEIGHBOURS.entries() {
+            self.print(format_args!(
+                "eid {e} smbus port {} addr {addr:#04x} mtu {}",
+                Routes::SMBUS_INDEX.0,
+                crate::smbus::SMBUS_MTU
+            ))
+            .await;
+        }
+        self.print(format_args!(
+            "default usb port {} mtu {}",
+            Routes::USB_INDEX.0,
+            self.routes.mtu()
+        ))
+        .await;
+    }
+
+    #[cfg(feature = "mctp-bench")]
+    async fn bench(&self, mut args: core::str::SplitAsciiWhitespace<'_>) {
+        let dest = args
+            .next()
+            .and_then(parse_u8)
+            .and_then(|e| Eid::new_normal(e).ok());
+        let count = args.next().and_then(|a| a.parse::<u64>().ok());
+        let len = args.next().map(|a| a.parse::<usize>().ok());
+        let (Some(dest), Some(count), len) = (dest, count, len) else {
+            self.print(format_args!("Usage: bench <eid> <count> [len]"))
+                .await;
+            return;
+        };
+        let len = match len {
+            None => BENCH_DEFAULT_LEN,
+            Some(Some(l)) if l >= MctpBench::BENCH_HEADER_LEN => l,
+            Some(_) => {
+                self.print(format_args!(
+                    "Length must be at least {}",
+                    MctpBench::BENCH_HEADER_LEN
+                ))
+                .await;
+                return;
+            }
+        };
+
+        let r = BenchRequest {
+            count,
+            len,
+            dest,
+            iid: None,
+            latency: false,
+            echo: false,
+            delay: None,
+            pattern: BenchPattern::Ramp,
+        };
+        if self.bench_control.start(r) {
+            self.print(format_args!("Bench to eid {dest} started"))
+                .await;
+        } else {
+            self.print(format_args!("All bench slots busy")).await;
+        }
+    }
+
+    async fn log(&self, mut args: core::str::SplitAsciiWhitespace<'_>) {
+        let (module, level) = match (args.next(), args.next()) {
+            (None, _) => {
+                self.print(format_args!(
+                    "log {} serial {}",
+                    multilog::level(),
+                    multilog::serial_level()
+                ))
+                .await;
+                return;
+            }
+            (Some(level), None) => (None, level),
+            (Some(module), Some(level)) => (Some(module), level),
+        };
+
+        let level = if level == "none" {
+            None
+        } else if let Ok(l) = LevelFilter::from_str(level) {
+            Some(l)
+        } else {
+            self.print(format_args!("Unknown level {level}")).await;
+            return;
+        };
+
+        match (module, level) {
+            (None, Some(level)) => {
+                let prev = multilog::level();
+                multilog::set_level(level);
+                info!("Log level {prev} -> {level}");
+            }
+            (None, None) => {
+                self.print(format_args!("Runtime level can't be none"))
+                    .await;
+                return;
+            }
+            (Some(multilog::SERIAL_TARGET), level) => {
+                let prev = multilog::set_serial_level(level);
+                let level = multilog::serial_level();
+                info!("Serial log level {prev} -> {level}");
+            }
+            (Some(module), level) => {
+                let Ok(prev) = multilog::set_module_level(module, level) else {
+                    self.print(format_args!("Can't set level for {module}"))
+                        .await;
+                    return;
+                };
+                info!("Log level {module} {prev:?} -> {level:?}");
+            }
+        }
+        self.print(format_args!("OK")).await;
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hex byte.
+#[cfg(feature = "mctp-bench")]
+fn parse_u8(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(h) => u8::from_str_radix(h, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
@@ -0,0 +1,205 @@
+//! MCTP over SMBus/I2C, as a second router port.
+//!
+//! NVMe drives expose NVMe-MI on the SMBus sideband as well as PCIe. The
+//! I2C1 target on the Arduino header (D15 SCL, D14 SDA) feeds the same
+//! router as MCTP over USB, so a single NVMe-MI listener and subsystem
+//! serve both transports.
+//!
+//! SMBus has no Get Endpoint ID style discovery of physical addresses
+//! here, so the I2C address of each peer EID is learned from its
+//! incoming packets, and replies are sent as an I2C controller.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::i2c::{self, I2c, MultiMaster, SlaveCommandKind};
+use embassy_stm32::mode::Async;
+use embassy_stm32::peripherals::{GPDMA1_CH4, GPDMA1_CH5, I2C1, PB8, PB9};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::Peri;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use heapless::Vec;
+use mctp::Eid;
+use mctp_estack::i2c::{MctpI2cEncap, MCTP_I2C_MAXMTU};
+use mctp_estack::router::{Port, PortId, Router};
+
+use crate::{Irqs, MCTP_BASELINE_MTU, MCTP_HEADER};
+
+/// 7-bit I2C address, the NVMe-MI default for the management endpoint.
+pub const OWN_ADDR: u8 = 0x1d;
+
+/// MTU for packets sent over SMBus, including the MCTP header.
+///
+/// SMBus peers are only required to accept the baseline MTU.
+pub const SMBUS_MTU: usize = MCTP_BASELINE_MTU + MCTP_HEADER;
+
+/// DSP0237 header: destination address, command code, byte count and
+/// source address.
+const I2C_HEADER: usize = 4;
+/// Largest I2C transfer, with the header and PEC.
+const I2C_BUF: usize = I2C_HEADER + MCTP_I2C_MAXMTU + 1;
+
+/// Peers with known I2C addresses. Further peers replace the oldest.
+const MAX_NEIGHBOURS: usize = 8;
+
+pub static NEIGHBOURS: Neighbours = Neighbours::new();
+
+/// EID to I2C address table, learned from incoming packets.
+pub struct Neighbours {
+    table: BlockingMutex<
+        CriticalSectionRawMutex,
+        RefCell<Vec<(Eid, u8), MAX_NEIGHBOURS>>,
+    >,
+}
+
+impl Neighbours {
+    const fn new() -> Self {
+        Self {
+            table: BlockingMutex::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Records the 7-bit I2C address `addr` for `eid`.
+    fn learn(&self, eid: Eid, addr: u8) {
+        self.table.lock(|t| {
+            let mut t = t.borrow_mut();
+            if let Some(e) = t.iter_mut().find(|(e, _)| *e == eid) {
+                if e.1 != addr {
+                    debug!("SMBus eid {eid} moved to address {addr:#04x}");
+                    e.1 = addr;
+                }
+                return;
+            }
+            if t.is_full() {
+                t.remove(0);
+            }
+            debug!("SMBus eid {eid} at address {addr:#04x}");
+            // Space was made above
+            let _ = t.push((eid, addr));
+        })
+    }
+
+    /// Returns the known EIDs and their I2C addresses.
+    #[cfg(feature = "usb-shell")]
+    pub fn entries(&self) -> Vec<(Eid, u8), MAX_NEIGHBOURS> {
+        self.table.lock(|t| t.borrow().clone())
+    }
+
+    /// Returns the I2C address for `eid`, if it has been seen on SMBus.
+    pub fn addr(&self, eid: Eid) -> Option<u8> {
+        self.table.lock(|t| {
+            t.borrow().iter().find(|(e, _)| *e == eid).map(|&(_, a)| a)
+        })
+    }
+}
+
+/// Sets up I2C1 as a target at `OWN_ADDR`, also able to send as a
+/// controller.
+pub(crate) fn setup(
+    i2c1: Peri<'static, I2C1>,
+    scl: Peri<'static, PB8>,
+    sda: Peri<'static, PB9>,
+    tx_dma: Peri<'static, GPDMA1_CH4>,
+    rx_dma: Peri<'static, GPDMA1_CH5>,
+) -> I2c<'static, Async, MultiMaster> {
+    let mut config = i2c::Config::default();
+    // SMBus 2.0 maximum
+    config.frequency = Hertz::khz(100);
+    I2c::new(i2c1, scl, sda, Irqs, tx_dma, rx_dma, config)
+        .into_slave_multimaster(i2c::SlaveAddrConfig::basic(OWN_ADDR))
+}
+
+/// Passes packets between the I2C bus and the router port `port`.
+#[embassy_executor::task]
+pub async fn smbus_task(
+    router: &'static Router<'static>,
+    mut i2c: I2c<'static, Async, MultiMaster>,
+    mut port: Port<'static>,
+    port_id: PortId,
+) -> ! {
+    let encap = MctpI2cEncap::new(OWN_ADDR);
+    let mut rx = [0u8; I2C_BUF];
+    let mut tx = [0u8; I2C_BUF];
+
+    info!("SMBus MCTP at address {OWN_ADDR:#04x}");
+    loop {
+        match select(i2c.listen(), port.outbound()).await {
+            Either::First(Ok(cmd)) => match cmd.kind {
+                SlaveCommandKind::Write => {
+                    // The controller doesn't send the destination
+                    // address byte, decode() expects it.
+                    rx[0] = OWN_ADDR << 1;
+                    let len = match i2c.respond_to_write(&mut rx[1..]).await {
+                        Ok(l) => l,
+                        Err(e) => {
+                            debug!("SMBus receive failed: {e:?}");
+                            continue;
+                        }
+                    };
+                    receive(router, &encap, &rx[..1 + len], port_id).await;
+                }
+                SlaveCommandKind::Read => {
+                    // MCTP over SMBus only uses writes
+                    trace!("SMBus read ignored");
+                    let _ = i2c.respond_to_read(&[]).await;
+                }
+            },
+            Either::First(Err(e)) => {
+                debug!("SMBus listen failed: {e:?}");
+            }
+            Either::Second((pkt, dest)) => {
+                let r = send(&mut i2c, &encap, pkt, dest, &mut tx).await;
+                port.outbound_done();
+                if let Err(e) = r {
+                    debug!("SMBus send to eid {dest} failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Decodes an I2C transfer, passing the MCTP packet to the router.
+async fn receive(
+    router: &Router<'_>,
+    encap: &MctpI2cEncap,
+    xfer: &[u8],
+    port_id: PortId,
+) {
+    let Ok((pkt, src_addr)) = encap.decode(xfer, true) else {
+        debug!("Bad SMBus packet, {} bytes", xfer.len());
+        return;
+    };
+    // Replies go to the EID's most recent address
+    if let &[_ver, _dest, source, ..] = pkt {
+        if let Ok(eid) = Eid::new_normal(source) {
+            NEIGHBOURS.learn(eid, src_addr);
+        }
+    }
+    router.inbound(pkt, port_id).await;
+}
+
+/// Sends an MCTP packet as an I2C controller.
+async fn send(
+    i2c: &mut I2c<'static, Async, MultiMaster>,
+    encap: &MctpI2cEncap,
+    pkt: &[u8],
+    dest: Eid,
+    tx: &mut [u8],
+) -> Result<(), &'static str> {
+    let addr = NEIGHBOURS.addr(dest).ok_or("unknown address")?;
+    let xfer = encap
+        .encode(addr, pkt, tx, true)
+        .map_err(|_| "packet too long")?;
+    // The address byte is sent by the peripheral
+    i2c.write(addr, &xfer[1..])
+        .await
+        .map_err(|_| "I2C write error")
+}
@@ -5,6 +5,17 @@
 
 //! Helpers for stm32h7s3 hardware
 
+use core::sync::atomic::{AtomicI32, Ordering};
+
+use embassy_stm32::adc::{Adc, SampleTime, Temperature, VrefInt};
+use embassy_stm32::pac;
+use embassy_stm32::peripherals::ADC2;
+use embassy_stm32::Peri;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::{Duration, Timer};
+
 pub fn device_id() -> [u8; 12] {
     let mut devid = [0u8; 12];
     /* Must read as u32 or u16. u8 is a BusFault */
@@ -16,3 +27,223 @@ pub fn device_id() -> [u8; 12] {
     }
     devid
 }
+
+/// Cause of the last reset, from RCC reset status flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetCause {
+    PowerOn,
+    Brownout,
+    /// NRST pin, including a debugger reset
+    Pin,
+    Software,
+    IndependentWatchdog,
+    WindowWatchdog,
+    /// Illegal Stop or Standby entry
+    LowPower,
+    Unknown,
+}
+
+/// Returns the cause of the last reset, and clears the flags.
+///
+/// Where several flags are set the most specific is returned, for example
+/// power-on also asserts the reset pin.
+pub fn reset_cause() -> ResetCause {
+    let rsr = pac::RCC.rsr().read();
+    let cause = if rsr.lpwrrstf() {
+        ResetCause::LowPower
+    } else if rsr.iwdgrstf() {
+        ResetCause::IndependentWatchdog
+    } else if rsr.wwdgrstf() {
+        ResetCause::WindowWatchdog
+    } else if rsr.sftrstf() {
+        ResetCause::Software
+    } else if rsr.porrstf() {
+        ResetCause::PowerOn
+    } else if rsr.borrstf() {
+        ResetCause::Brownout
+    } else if rsr.pinrstf() {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    };
+    pac::RCC.rsr().modify(|w| w.set_rmvf(true));
+    cause
+}
+
+/// Resets the system.
+pub fn reboot() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/* BOOT_MAILBOX in memory.x, matching xspiloader */
+const BOOT_MAILBOX: usize = 0x2403_fff0;
+const BOOT_MAILBOX_MAGIC: u32 = u32::from_le_bytes(*b"BOOT");
+const STAY_MAGIC: u32 = u32::from_le_bytes(*b"STAY");
+
+/// Resets the system, requesting `xspiloader` to stay resident rather
+/// than booting an image.
+///
+/// The loader clears the request, so the following reset boots normally.
+pub fn reboot_to_loader() -> ! {
+    let mb = BOOT_MAILBOX as *mut u32;
+    // Safety: reserved region, only accessed by the loader and here.
+    unsafe {
+        mb.add(2).write_volatile(STAY_MAGIC);
+        mb.add(3).write_volatile(!STAY_MAGIC);
+    }
+    reboot()
+}
+
+/// Reason for booting, reported by `xspiloader`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootReason {
+    /// Confirmed or only image slot
+    Normal,
+    /// First boot of a newly activated slot
+    Trial,
+    /// The active slot wasn't confirmed, booted the other slot
+    Reverted,
+    /// The selected slot failed to load, booted the other slot
+    Fallback,
+    Unknown(u8),
+}
+
+impl From<u8> for BootReason {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => Self::Normal,
+            1 => Self::Trial,
+            2 => Self::Reverted,
+            3 => Self::Fallback,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BootMailbox {
+    pub reason: BootReason,
+    /// Image slot, 0 for A, 1 for B
+    pub slot: u8,
+}
+
+/// Reads and clears the boot mailbox written by `xspiloader`.
+///
+/// Returns `None` if the loader didn't write it, such as an older
+/// loader or booting from a debugger.
+pub fn boot_mailbox() -> Option<BootMailbox> {
+    let mb = BOOT_MAILBOX as *mut u32;
+    // Safety: reserved region, only accessed by the loader and here.
+    let (magic, val) = unsafe {
+        let m = mb.read_volatile();
+        let v = mb.add(1).read_volatile();
+        mb.write_volatile(0);
+        (m, v)
+    };
+
+    (magic == BOOT_MAILBOX_MAGIC).then(|| BootMailbox {
+        reason: BootReason::from(val as u8),
+        slot: (val >> 8) as u8,
+    })
+}
+
+/// Internal die temperature sensor and voltage reference.
+///
+/// Both are internally connected to ADC2. The die temperature is sampled
+/// by `sensor_task()`, the voltage reference on demand.
+struct Sensors {
+    adc: Adc<'static, ADC2>,
+    temperature: Temperature,
+    vrefint: VrefInt,
+}
+
+static SENSORS: OnceLock<Mutex<CriticalSectionRawMutex, Sensors>> =
+    OnceLock::new();
+
+/// Sets up the internal sensors. Must be called once at startup.
+pub fn init_sensors(adc2: Peri<'static, ADC2>) {
+    let mut adc = Adc::new(adc2);
+    // Longest sample time, temperature sensor requires at least 9us
+    adc.set_sample_time(SampleTime::from_bits(0b111));
+    let temperature = adc.enable_temperature();
+    let vrefint = adc.enable_vrefint();
+    let _ = SENSORS.init(Mutex::new(Sensors {
+        adc,
+        temperature,
+        vrefint,
+    }));
+}
+
+/// Interval between die temperature samples.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `DIE_TEMPERATURE` before a valid sample.
+const NO_TEMPERATURE: i32 = i32::MIN;
+
+/// Last die temperature sample in degrees Celsius.
+static DIE_TEMPERATURE: AtomicI32 = AtomicI32::new(NO_TEMPERATURE);
+
+/// Returns the last sampled die temperature in degrees Celsius.
+///
+/// Returns `None` before `sensor_task()` has sampled it, or if the factory
+/// calibration is unprogrammed.
+pub fn die_temperature() -> Option<i32> {
+    let t = DIE_TEMPERATURE.load(Ordering::Relaxed);
+    (t != NO_TEMPERATURE).then_some(t)
+}
+
+/// Samples the die temperature periodically, for `die_temperature()`.
+///
+/// ADC conversions block, so callers read the cached sample instead.
+#[embassy_executor::task]
+pub async fn sensor_task() -> ! {
+    loop {
+        let t = sample_die_temperature().await.unwrap_or(NO_TEMPERATURE);
+        DIE_TEMPERATURE.store(t, Ordering::Relaxed);
+        Timer::after(SAMPLE_INTERVAL).await;
+    }
+}
+
+async fn sample_die_temperature() -> Option<i32> {
+    /* Factory calibration at 30 and 130 degrees C, VDDA = 3.3V */
+    const TS_CAL1: usize = 0x08FF_F814;
+    const TS_CAL2: usize = 0x08FF_F818;
+    const CAL1_TEMP: i32 = 30;
+    const CAL2_TEMP: i32 = 130;
+
+    let mut s = SENSORS.try_get()?.lock().await;
+    let s = &mut *s;
+
+    /* Must read as u32 or u16 */
+    let cal1 = unsafe { (TS_CAL1 as *const u16).read_volatile() } as i32;
+    let cal2 = unsafe { (TS_CAL2 as *const u16).read_volatile() } as i32;
+    if cal2 == cal1 {
+        // Unprogrammed calibration
+        return None;
+    }
+    let sample = s.adc.blocking_read(&mut s.temperature) as i32;
+    Some(CAL1_TEMP + (sample - cal1) * (CAL2_TEMP - CAL1_TEMP) / (cal2 - cal1))
+}
+
+/// Returns the VDDA supply voltage in millivolts, measured against
+/// the internal voltage reference.
+///
+/// Returns `None` before `init_sensors()`, or if the factory
+/// calibration is unprogrammed.
+pub async fn supply_millivolts() -> Option<u32> {
+    /* Factory calibration of VREFINT, VDDA = 3.3V */
+    const VREFINT_CAL: usize = 0x08FF_F810;
+    const CAL_MV: u32 = 3300;
+
+    let mut s = SENSORS.try_get()?.lock().await;
+    let s = &mut *s;
+
+    /* Must read as u32 or u16 */
+    let cal = unsafe { (VREFINT_CAL as *const u16).read_volatile() } as u32;
+    let sample = s.adc.blocking_read(&mut s.vrefint) as u32;
+
+    if cal == 0 || cal == 0xffff || sample == 0 {
+        return None;
+    }
+    Some(CAL_MV * cal / sample)
+}
@@ -5,13 +5,15 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+use core::cell::Cell;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use embassy_executor::Spawner;
 use embassy_stm32::peripherals::USB_OTG_HS;
 use embassy_stm32::usb::{DmPin, DpPin, Driver};
 use embassy_stm32::{bind_interrupts, usb, Peri};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::signal::Signal;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 #[allow(unused_imports)]
 use embassy_usb::class::cdc_acm;
 use embassy_usb::Builder;
@@ -20,10 +22,147 @@ use mctp_estack::router::{Port, PortId, Router};
 use mctp_usb_embassy::{MctpUsbClass, MCTP_USB_MAX_PACKET};
 use static_cell::StaticCell;
 
+use crate::reassembly::REASSEMBLY;
+use crate::settings::UsbIdentity;
+use crate::watchdog::{Liveness, LIVENESS};
+use crate::SignalCS;
+
 bind_interrupts!(struct Irqs {
     OTG_HS => usb::InterruptHandler<USB_OTG_HS>;
 });
 
+/// Default USB identity, unless set in persistent settings
+const USB_VID: u16 = 0x3834;
+const USB_PID: u16 = 0x0000;
+const MANUFACTURER: &str = "Code Construct";
+
+const CONTROL_SZ: usize = 64;
+
+/// Maximum string descriptor length in UTF-16 units.
+///
+/// embassy-usb builds string descriptors in the control buffer.
+const MAX_STRING_UNITS: usize = (CONTROL_SZ - 4) / 2;
+
+/// Returns a string to use for a descriptor, `None` for the default.
+fn usb_string(s: &str) -> Option<&str> {
+    if s.is_empty() {
+        return None;
+    }
+    if s.encode_utf16().count() > MAX_STRING_UNITS {
+        warn!("USB string \"{s}\" too long, using default");
+        return None;
+    }
+    Some(s)
+}
+
+/// USB link statistics, reported by diagnostics.
+///
+/// The embassy driver doesn't report bus errors, so those aren't counted.
+pub struct UsbStats {
+    pub resets: AtomicU32,
+    pub suspends: AtomicU32,
+    pub resumes: AtomicU32,
+    pub configured: AtomicBool,
+}
+
+pub static USB_STATS: UsbStats = UsbStats {
+    resets: AtomicU32::new(0),
+    suspends: AtomicU32::new(0),
+    resumes: AtomicU32::new(0),
+    configured: AtomicBool::new(false),
+};
+
+/// USB state reported to the application.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsbState {
+    Down,
+    /// Configured by the host, at the enumerated speed
+    Up(UsbSpeed),
+}
+
+/// Last notified state, shared by `usb_task` and `StatsHandler`
+static LAST_STATE: BlockingMutex<
+    CriticalSectionRawMutex,
+    Cell<Option<UsbState>>,
+> = BlockingMutex::new(Cell::new(None));
+
+/// Notifies a state change. Repeated events for the same state are
+/// ignored.
+fn notify(state_notify: &SignalCS<UsbState>, state: UsbState) {
+    let prev = LAST_STATE.lock(|l| l.replace(Some(state)));
+    if prev == Some(state) {
+        debug!("Ignoring duplicate USB state {state:?}");
+    } else {
+        state_notify.signal(state);
+    }
+}
+
+/// Returns the state to notify once configured.
+fn up_state() -> UsbState {
+    let speed = link_speed();
+    if speed != UsbSpeed::High {
+        error!(
+            "USB enumerated at {speed:?} speed, high speed is required \
+            for 512 byte MCTP packets. Check for a USB 1.1 hub or cable."
+        );
+    }
+    UsbState::Up(speed)
+}
+
+/// Updates `USB_STATS` from device events, and notifies configuration.
+struct StatsHandler {
+    state_notify: &'static SignalCS<UsbState>,
+}
+
+impl embassy_usb::Handler for StatsHandler {
+    fn reset(&mut self) {
+        USB_STATS.resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        USB_STATS.configured.store(configured, Ordering::Relaxed);
+        let state = if configured {
+            up_state()
+        } else {
+            UsbState::Down
+        };
+        notify(self.state_notify, state);
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        if suspended {
+            USB_STATS.suspends.fetch_add(1, Ordering::Relaxed);
+        } else {
+            USB_STATS.resumes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsbSpeed {
+    High = 0,
+    Full = 1,
+    /// Reserved `ENUMSPD` value
+    Unknown = 0xff,
+}
+
+/// Returns the enumerated USB speed.
+///
+/// Only valid after a bus reset.
+pub fn link_speed() -> UsbSpeed {
+    // OTG_DSTS register
+    const DSTS: usize = 0x808;
+    let dsts = embassy_stm32::pac::USB_OTG_HS.as_ptr() as usize + DSTS;
+    let dsts = unsafe { (dsts as *const u32).read_volatile() };
+    // ENUMSPD field
+    match (dsts >> 1) & 0b11 {
+        0b00 => UsbSpeed::High,
+        0b01 | 0b11 => UsbSpeed::Full,
+        _ => UsbSpeed::Unknown,
+    }
+}
+
 #[cfg(feature = "log-usbserial")]
 type Endpoints = (
     MctpUsbClass<'static, Driver<'static, USB_OTG_HS>>,
@@ -37,12 +176,30 @@ pub(crate) fn setup(
     usb: Peri<'static, USB_OTG_HS>,
     dp: Peri<'static, impl DpPin<USB_OTG_HS>>,
     dm: Peri<'static, impl DmPin<USB_OTG_HS>>,
-    state_notify: &'static Signal<CriticalSectionRawMutex, bool>,
+    state_notify: &'static SignalCS<UsbState>,
+    identity: Option<UsbIdentity>,
 ) -> Endpoints {
-    let mut config = embassy_usb::Config::new(0x3834, 0x0000);
-    config.manufacturer = Some("Code Construct");
+    let mut config = match &identity {
+        Some(id) => {
+            info!("USB identity {:04x}:{:04x}", id.vid, id.pid);
+            embassy_usb::Config::new(id.vid, id.pid)
+        }
+        None => embassy_usb::Config::new(USB_VID, USB_PID),
+    };
+    config.manufacturer = Some(MANUFACTURER);
     config.product = Some(crate::PRODUCT);
 
+    if let Some(id) = identity {
+        static IDENTITY: StaticCell<UsbIdentity> = StaticCell::new();
+        let id: &'static UsbIdentity = IDENTITY.init(id);
+        if let Some(m) = usb_string(&id.manufacturer) {
+            config.manufacturer = Some(m);
+        }
+        if let Some(p) = usb_string(&id.product) {
+            config.product = Some(p);
+        }
+    }
+
     // USB serial number matches the first 12 digits of the mctp uuid
     static SERIAL: StaticCell<String<{ uuid::fmt::Simple::LENGTH }>> =
         StaticCell::new();
@@ -54,7 +211,6 @@ pub(crate) fn setup(
     // TODO: is vbus detection needed? Seems not on the nucleo?
     // driver_config.vbus_detection = true;
 
-    const CONTROL_SZ: usize = 64;
     const USBSERIAL_SZ: usize = 64;
     // TODO: +1 workaround can be removed once this merges:
     // https://github.com/embassy-rs/embassy/pull/3892
@@ -81,6 +237,9 @@ pub(crate) fn setup(
         control_buf,
     );
 
+    static HANDLER: StaticCell<StatsHandler> = StaticCell::new();
+    builder.handler(HANDLER.init(StatsHandler { state_notify }));
+
     let mctp = MctpUsbClass::new(&mut builder);
 
     #[cfg(feature = "log-usbserial")]
@@ -99,16 +258,26 @@ pub(crate) fn setup(
     ret
 }
 
+/// Runs the USB device, notifying of up/down state.
+///
+/// USB is up once configured by the host, notified by `StatsHandler`,
+/// or on resume if already configured. Suspend is down.
+///
+/// Only state changes are notified, repeated events for the same
+/// state are ignored.
 #[embassy_executor::task]
 async fn usb_task(
     mut usb: embassy_usb::UsbDevice<'static, Driver<'static, USB_OTG_HS>>,
-    state_notify: &'static Signal<CriticalSectionRawMutex, bool>,
+    state_notify: &'static SignalCS<UsbState>,
 ) -> ! {
     loop {
         usb.wait_resume().await;
-        state_notify.signal(true);
+        // A host may resume without reconfiguring
+        if USB_STATS.configured.load(Ordering::Relaxed) {
+            notify(state_notify, up_state());
+        }
         usb.run_until_suspend().await;
-        state_notify.signal(false);
+        notify(state_notify, UsbState::Down);
     }
 }
 
@@ -121,7 +290,42 @@ pub async fn usb_recv_task(
     >,
     port: PortId,
 ) -> ! {
-    usb_receiver.run(router, port).await;
+    LIVENESS
+        .monitor(Liveness::USB_RECV, recv_loop(router, usb_receiver, port))
+        .await
+}
+
+/// As `Receiver::run()`, also recording packets in `REASSEMBLY`.
+async fn recv_loop(
+    router: &'static Router<'static>,
+    mut usb_receiver: mctp_usb_embassy::Receiver<
+        'static,
+        Driver<'static, USB_OTG_HS>,
+    >,
+    port: PortId,
+) -> ! {
+    loop {
+        debug!("mctp usb recv waiting");
+        usb_receiver.wait_connection().await;
+        info!("mctp usb recv attached");
+
+        loop {
+            match usb_receiver.receive().await {
+                Some(Ok(pkt)) => {
+                    REASSEMBLY.packet(pkt);
+                    router.inbound(pkt, port).await;
+                }
+                Some(Err(e)) => {
+                    debug!("mctp usb packet decode failure {e}")
+                }
+                None => {
+                    info!("mctp usb disconnected");
+                    REASSEMBLY.clear();
+                    break;
+                }
+            }
+        }
+    }
 }
 
 #[embassy_executor::task]
@@ -129,5 +333,7 @@ pub async fn usb_send_task(
     mctp_usb_bottom: Port<'static>,
     usb_sender: mctp_usb_embassy::Sender<'static, Driver<'static, USB_OTG_HS>>,
 ) -> ! {
-    usb_sender.run(mctp_usb_bottom).await;
+    LIVENESS
+        .monitor(Liveness::USB_SEND, usb_sender.run(mctp_usb_bottom))
+        .await
 }
@@ -0,0 +1,127 @@
+//! Independent watchdog, reset if critical tasks stop running.
+//!
+//! Monitored tasks check in periodically. The watchdog is only kicked
+//! once all have checked in, so a task or executor that stops being
+//! polled eventually causes a reset.
+
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+use core::future::Future;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::peripherals::IWDG;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_stm32::Peri;
+use embassy_time::{Duration, Timer};
+
+use crate::lastpanic;
+use crate::led::LED_STATUS;
+
+/// Independent watchdog timeout.
+///
+/// The watchdog is usually started by `xspiloader`, and once started
+/// can't be stopped.
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// Interval between checking liveness and kicking the watchdog.
+const KICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consecutive missed kicks before the watchdog resets.
+const MISSED_KICKS: u32 = 3;
+const _: () = assert!(
+    KICK_INTERVAL.as_ticks() * (MISSED_KICKS as u64 + 1)
+        <= WATCHDOG_TIMEOUT.as_ticks()
+);
+
+/// Interval between task check-ins, several per `KICK_INTERVAL`.
+const CHECKIN_INTERVAL: Duration = Duration::from_millis(250);
+
+pub static LIVENESS: Liveness = Liveness::new();
+
+/// Liveness flags, set by monitored tasks.
+pub struct Liveness {
+    flags: AtomicU32,
+}
+
+impl Liveness {
+    pub const USB_RECV: u32 = 1 << 0;
+    pub const USB_SEND: u32 = 1 << 1;
+    pub const CONTROL: u32 = 1 << 2;
+    const ALL: u32 = Self::USB_RECV | Self::USB_SEND | Self::CONTROL;
+
+    const fn new() -> Self {
+        Self {
+            flags: AtomicU32::new(0),
+        }
+    }
+
+    pub fn alive(&self, flag: u32) {
+        self.flags.fetch_or(flag, Ordering::Relaxed);
+    }
+
+    /// Runs `fut`, checking in while the task is being polled.
+    ///
+    /// This detects a task or executor that is no longer polled, such as
+    /// a busy loop at equal or higher priority. A future that is
+    /// polled but never completes is not detected.
+    pub async fn monitor<F: Future>(&self, flag: u32, fut: F) -> F::Output {
+        let checkin = async {
+            loop {
+                self.alive(flag);
+                Timer::after(CHECKIN_INTERVAL).await;
+            }
+        };
+        match select(fut, checkin).await {
+            Either::First(r) => r,
+            Either::Second(_) => unreachable!(),
+        }
+    }
+
+    /// Returns the flags that were not set, and clears all flags.
+    fn take_missing(&self) -> u32 {
+        !self.flags.swap(0, Ordering::Relaxed) & Self::ALL
+    }
+}
+
+pub fn setup(iwdg: Peri<'static, IWDG>) -> IndependentWatchdog<'static, IWDG> {
+    let mut wdg =
+        IndependentWatchdog::new(iwdg, WATCHDOG_TIMEOUT.as_micros() as u32);
+    // Already running if started by xspiloader
+    wdg.unleash();
+    wdg
+}
+
+/// Kicks the independent watchdog while all monitored tasks are alive.
+#[embassy_executor::task]
+pub async fn watchdog_task(mut wdg: IndependentWatchdog<'static, IWDG>) -> ! {
+    let mut missed = 0;
+    loop {
+        Timer::after(KICK_INTERVAL).await;
+
+        let missing = LIVENESS.take_missing();
+        if missing == 0 {
+            if missed > 0 {
+                info!("Watchdog liveness recovered");
+            }
+            missed = 0;
+            wdg.pet();
+            continue;
+        }
+
+        missed += 1;
+        warn!("Watchdog liveness missing {missing:#x}");
+        LED_STATUS.fault();
+        if missed == MISSED_KICKS {
+            // Reported after reset
+            lastpanic::save_message(format_args!(
+                "Watchdog reset, liveness missing {missing:#x}"
+            ));
+        }
+    }
+}
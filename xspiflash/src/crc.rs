@@ -0,0 +1,68 @@
+//! CRC-32 (IEEE 802.3), as used by zlib and `crc32(1)`.
+//!
+//! Reflected polynomial `0x04c11db7` (`0xedb88320` reversed), initial
+//! value and final XOR `0xffffffff`. This is `CRC-32/ISO-HDLC` in the
+//! CRC catalogue, Python's `zlib.crc32()`.
+
+// SPDX-License-Identifier: MIT OR Apache-2.0
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+
+const POLY: u32 = 0xedb8_8320;
+
+const TABLE: [u32; 256] = {
+    let mut t = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { POLY ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        t[i] = c;
+        i += 1;
+    }
+    t
+};
+
+/// Incremental CRC-32.
+///
+/// Data may be passed to `update()` in any number of pieces.
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { crc: 0xffff_ffff }
+    }
+
+    pub const fn update(&mut self, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            self.crc = TABLE[((self.crc ^ data[i] as u32) & 0xff) as usize]
+                ^ (self.crc >> 8);
+            i += 1;
+        }
+    }
+
+    pub const fn finalize(&self) -> u32 {
+        !self.crc
+    }
+}
+
+// Catalogue check value
+const _: () = {
+    let mut c = Crc32::new();
+    c.update(b"123456789");
+    assert!(c.finalize() == 0xcbf4_3926);
+};
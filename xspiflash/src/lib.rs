@@ -0,0 +1,553 @@
+//! External XSPI flash access, shared by `xspiloader` and usbnvme.
+//!
+//! Commands are single line SPI, selected from `FLASH_PARTS` by JEDEC ID.
+//! Reads use quad I/O where the part supports it.
+//!
+//! Reads can also be memory-mapped with `FlashMemory::read_mapped()`,
+//! streaming faster than indirect reads.
+//!
+//! Erase and program are only permitted within regions set by
+//! `FlashMemory::set_writable()`, none by default. This prevents
+//! overwriting the loader's image or the running application.
+
+// SPDX-License-Identifier: MIT OR Apache-2.0
+/*
+ * Copyright (c) 2025 Code Construct
+ */
+#![no_std]
+
+pub mod crc;
+
+use core::ops::Range;
+use core::sync::atomic::{Ordering, compiler_fence};
+
+#[allow(unused)]
+use log::{debug, error, info, trace, warn};
+
+use embassy_stm32::mode::Blocking;
+use embassy_stm32::xspi::{
+    AddressSize, ChipSelectHighTime, DummyCycles, FIFOThresholdLevel, Instance,
+    MemorySize, MemoryType, TransferConfig, WrapSize, Xspi, XspiWidth,
+};
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 256;
+
+const CMD_READ_ID: u8 = 0x9F;
+const CMD_READ_SR: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+/// Fast Read Quad I/O, 4 line address and data
+const CMD_QUAD_IO_READ: u8 = 0xEB;
+/// Following the mode byte for `CMD_QUAD_IO_READ`
+const QUAD_IO_READ_DUMMY: DummyCycles = DummyCycles::_4;
+
+/// Status register Write In Progress
+const SR_WIP: u8 = 0x01;
+
+/// Memory-mapped flash address for XSPI1
+pub const XSPI1_MAPPED_BASE: usize = 0x9000_0000;
+/// Memory-mapped flash address for XSPI2, `EXT_FLASH` in memory.x
+pub const XSPI2_MAPPED_BASE: usize = 0x7000_0000;
+
+/// Memory-mapped reads use 24-bit addresses, so larger parts alias
+/// after 16MB.
+const MAPPED_LIMIT: usize = 1 << 24;
+
+#[derive(Debug)]
+pub enum Error {
+    /// XSPI peripheral error
+    Xspi,
+    /// Address range is out of bounds or not writable
+    Range,
+    /// JEDEC ID not in `FLASH_PARTS`
+    UnknownPart([u8; 3]),
+}
+
+/// XSPI configuration for single line or quad SPI flash at 64MHz.
+pub fn config() -> embassy_stm32::xspi::Config {
+    embassy_stm32::xspi::Config {
+        fifo_threshold: FIFOThresholdLevel::_4Bytes,
+        // Only affects octal DTR modes, not used here.
+        memory_type: MemoryType::Macronix,
+        delay_hold_quarter_cycle: true,
+        device_size: MemorySize::_32MiB,
+        chip_select_high_time: ChipSelectHighTime::_2Cycle,
+        free_running_clock: false,
+        clock_mode: false,
+        wrap_size: WrapSize::None,
+        // 64MHz
+        clock_prescaler: 0,
+        sample_shifting: false,
+        chip_select_boundary: 0,
+        max_transfer: 0,
+        refresh: 0,
+    }
+}
+
+/// Location of the Quad Enable status bit.
+struct QuadEnable {
+    /// Command to read the register containing QE
+    read: u8,
+    /// Command to write the register
+    write: u8,
+    /// QE bit mask
+    bit: u8,
+}
+
+/// Commands for a flash part in single line SPI mode.
+///
+/// All supported parts use the common write enable, page program and
+/// 4kB sector erase commands.
+pub struct FlashPart {
+    pub name: &'static str,
+    /// JEDEC manufacturer, memory type, capacity
+    jedec_id: [u8; 3],
+    /// Size in bytes
+    size: usize,
+    read: u8,
+    read_dummy: DummyCycles,
+    enable_reset: u8,
+    reset: u8,
+    /// `None` if the part doesn't support quad I/O
+    quad: Option<QuadEnable>,
+}
+
+const WINBOND_QE: QuadEnable = QuadEnable {
+    read: 0x35,
+    write: 0x31,
+    bit: 0x02,
+};
+
+const ISSI_QE: QuadEnable = QuadEnable {
+    read: 0x05,
+    write: 0x01,
+    bit: 0x40,
+};
+
+/// Supported flash parts, detected by JEDEC ID.
+pub const FLASH_PARTS: &[FlashPart] = &[
+    // Nucleo-H7S3L8
+    FlashPart {
+        name: "Macronix MX25UW25645G",
+        jedec_id: [0xc2, 0x81, 0x39],
+        size: 32 * 1024 * 1024,
+        read: 0x0B,
+        read_dummy: DummyCycles::_8,
+        enable_reset: 0x66,
+        reset: 0x99,
+        // Octal part, no quad mode
+        quad: None,
+    },
+    FlashPart {
+        name: "Winbond W25Q256JV",
+        jedec_id: [0xef, 0x40, 0x19],
+        size: 32 * 1024 * 1024,
+        read: 0x0B,
+        read_dummy: DummyCycles::_8,
+        enable_reset: 0x66,
+        reset: 0x99,
+        quad: Some(WINBOND_QE),
+    },
+    FlashPart {
+        name: "Winbond W25Q128JV",
+        jedec_id: [0xef, 0x40, 0x18],
+        size: 16 * 1024 * 1024,
+        read: 0x0B,
+        read_dummy: DummyCycles::_8,
+        enable_reset: 0x66,
+        reset: 0x99,
+        quad: Some(WINBOND_QE),
+    },
+    FlashPart {
+        name: "ISSI IS25LP256D",
+        jedec_id: [0x9d, 0x60, 0x19],
+        size: 32 * 1024 * 1024,
+        read: 0x0B,
+        read_dummy: DummyCycles::_8,
+        enable_reset: 0x66,
+        reset: 0x99,
+        quad: Some(ISSI_QE),
+    },
+];
+
+/// Access to the flash chip.
+pub struct FlashMemory<I: Instance> {
+    xspi: Xspi<'static, I, Blocking>,
+    part: &'static FlashPart,
+    /// Reads use `CMD_QUAD_IO_READ`
+    quad: bool,
+    /// Regions that may be erased or programmed
+    writable: &'static [Range<u32>],
+}
+
+impl<I: Instance> FlashMemory<I> {
+    /// Detects the flash part and resets it.
+    ///
+    /// Nothing is writable until `set_writable()` is called.
+    pub fn new(mut xspi: Xspi<'static, I, Blocking>) -> Result<Self, Error> {
+        let id = Self::read_jedec_id(&mut xspi)?;
+        let Some(part) = FLASH_PARTS.iter().find(|p| p.jedec_id == id) else {
+            error!("Unrecognised flash JEDEC ID {:02x?}", id);
+            return Err(Error::UnknownPart(id));
+        };
+        info!("Flash {} JEDEC ID {:02x?}", part.name, id);
+
+        let mut memory = Self {
+            xspi,
+            part,
+            quad: false,
+            writable: &[],
+        };
+        memory.reset_memory()?;
+        memory.quad = memory.enable_quad();
+        Ok(memory)
+    }
+
+    /// Sets the regions that may be erased or programmed.
+    pub fn set_writable(&mut self, regions: &'static [Range<u32>]) {
+        self.writable = regions;
+    }
+
+    pub fn part(&self) -> &'static FlashPart {
+        self.part
+    }
+
+    /// Size of the flash in bytes.
+    pub fn size(&self) -> usize {
+        self.part.size
+    }
+
+    /// Returns whether reads use quad I/O.
+    pub fn quad(&self) -> bool {
+        self.quad
+    }
+
+    /// Sets the Quad Enable bit, required for quad I/O reads.
+    ///
+    /// Returns false if quad isn't supported or the bit didn't set.
+    fn enable_quad(&mut self) -> bool {
+        let Some(qe) = &self.part.quad else {
+            info!("Using single line read");
+            return false;
+        };
+
+        let Ok(sr) = self.read_register(qe.read) else {
+            warn!("Quad enable failed, using single line read");
+            return false;
+        };
+        if sr & qe.bit == 0 {
+            let write = TransferConfig {
+                iwidth: XspiWidth::SING,
+                adwidth: XspiWidth::NONE,
+                dwidth: XspiWidth::SING,
+                instruction: Some(qe.write as u32),
+                address: None,
+                dummy: DummyCycles::_0,
+                ..Default::default()
+            };
+            let r = self.exec_command(CMD_WRITE_ENABLE).and_then(|_| {
+                self.xspi
+                    .blocking_write(&[sr | qe.bit], write)
+                    .map_err(|_| Error::Xspi)
+            });
+            if r.and_then(|_| self.wait_write_finish()).is_err() {
+                warn!("Quad enable failed, using single line read");
+                return false;
+            }
+        }
+
+        if !self.read_register(qe.read).is_ok_and(|sr| sr & qe.bit != 0) {
+            warn!("Quad enable didn't set, using single line read");
+            return false;
+        }
+        info!("Using quad read");
+        true
+    }
+
+    /// Reads the JEDEC manufacturer and device ID.
+    ///
+    /// The command is common to all parts.
+    fn read_jedec_id(
+        xspi: &mut Xspi<'static, I, Blocking>,
+    ) -> Result<[u8; 3], Error> {
+        let mut id = [0; 3];
+        let transaction = TransferConfig {
+            iwidth: XspiWidth::SING,
+            isize: AddressSize::_8bit,
+            adwidth: XspiWidth::NONE,
+            dwidth: XspiWidth::SING,
+            instruction: Some(CMD_READ_ID as u32),
+            address: None,
+            dummy: DummyCycles::_0,
+            ..Default::default()
+        };
+        xspi.blocking_read(&mut id, transaction)
+            .map_err(|_| Error::Xspi)?;
+        Ok(id)
+    }
+
+    fn exec_command(&mut self, cmd: u8) -> Result<(), Error> {
+        let transaction = TransferConfig {
+            iwidth: XspiWidth::SING,
+            adwidth: XspiWidth::NONE,
+            dwidth: XspiWidth::NONE,
+            instruction: Some(cmd as u32),
+            address: None,
+            dummy: DummyCycles::_0,
+            ..Default::default()
+        };
+        self.xspi
+            .blocking_command(&transaction)
+            .map_err(|_| Error::Xspi)
+    }
+
+    fn exec_address_command(
+        &mut self,
+        cmd: u8,
+        addr: u32,
+    ) -> Result<(), Error> {
+        let transaction = TransferConfig {
+            iwidth: XspiWidth::SING,
+            adwidth: XspiWidth::SING,
+            adsize: AddressSize::_24bit,
+            dwidth: XspiWidth::NONE,
+            instruction: Some(cmd as u32),
+            address: Some(addr),
+            dummy: DummyCycles::_0,
+            ..Default::default()
+        };
+        self.xspi
+            .blocking_command(&transaction)
+            .map_err(|_| Error::Xspi)
+    }
+
+    fn reset_memory(&mut self) -> Result<(), Error> {
+        self.exec_command(self.part.enable_reset)?;
+        self.exec_command(self.part.reset)?;
+        self.wait_write_finish()
+    }
+
+    fn read_register(&mut self, cmd: u8) -> Result<u8, Error> {
+        let mut buffer = [0; 1];
+        let transaction = TransferConfig {
+            iwidth: XspiWidth::SING,
+            isize: AddressSize::_8bit,
+            adwidth: XspiWidth::NONE,
+            dwidth: XspiWidth::SING,
+            instruction: Some(cmd as u32),
+            address: None,
+            dummy: DummyCycles::_0,
+            ..Default::default()
+        };
+        self.xspi
+            .blocking_read(&mut buffer, transaction)
+            .map_err(|_| Error::Xspi)?;
+        Ok(buffer[0])
+    }
+
+    pub fn read_sr(&mut self) -> Result<u8, Error> {
+        self.read_register(CMD_READ_SR)
+    }
+
+    /// Returns whether an erase or program is in progress.
+    pub fn write_in_progress(&mut self) -> Result<bool, Error> {
+        Ok(self.read_sr()? & SR_WIP != 0)
+    }
+
+    /// Waits for an erase or program to complete, without yielding.
+    pub fn wait_write_finish(&mut self) -> Result<(), Error> {
+        while self.write_in_progress()? {}
+        Ok(())
+    }
+
+    pub fn read(&mut self, addr: u32, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let end = (addr as usize)
+            .checked_add(buffer.len())
+            .ok_or(Error::Range)?;
+        if end > self.size() {
+            return Err(Error::Range);
+        }
+
+        let transaction = self.read_transaction(Some(addr));
+        self.xspi
+            .blocking_read(buffer, transaction)
+            .map_err(|_| Error::Xspi)
+    }
+
+    /// Calls `f` with the flash contents memory-mapped at `base`.
+    ///
+    /// Reads use the same command as `read()`. The quad I/O mode byte
+    /// doesn't enter continuous read (XIP), and single line reads need
+    /// no enter or exit sequence, so commands can be issued again once
+    /// the mapping is aborted on return.
+    ///
+    /// The slice is limited to the first 16MB.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the memory-mapped region for the `I` instance,
+    /// such as `XSPI2_MAPPED_BASE`.
+    pub unsafe fn read_mapped<R>(
+        &mut self,
+        base: usize,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, Error> {
+        let read = self.read_transaction(None);
+        // Writes aren't permitted through the mapping
+        let write = TransferConfig {
+            iwidth: XspiWidth::NONE,
+            adwidth: XspiWidth::NONE,
+            dwidth: XspiWidth::NONE,
+            ..Default::default()
+        };
+        self.xspi
+            .enable_memory_mapped_mode(read, write)
+            .map_err(|_| Error::Xspi)?;
+        compiler_fence(Ordering::SeqCst);
+
+        let len = self.size().min(MAPPED_LIMIT);
+        // Safety: the caller ensures `base` is this instance's region,
+        // readable until the mapping is disabled below.
+        let mapped =
+            unsafe { core::slice::from_raw_parts(base as *const u8, len) };
+        let r = f(mapped);
+
+        compiler_fence(Ordering::SeqCst);
+        self.xspi.disable_memory_mapped_mode();
+        Ok(r)
+    }
+
+    /// Returns the read command for the part, quad I/O if enabled.
+    ///
+    /// `addr` is `None` for memory-mapped reads.
+    fn read_transaction(&self, addr: Option<u32>) -> TransferConfig {
+        if self.quad {
+            TransferConfig {
+                iwidth: XspiWidth::SING,
+                adwidth: XspiWidth::QUAD,
+                adsize: AddressSize::_24bit,
+                // Mode byte, not continuous read
+                abwidth: XspiWidth::QUAD,
+                alternate_bytes: Some(0x00),
+                absize: AddressSize::_8bit,
+                dwidth: XspiWidth::QUAD,
+                instruction: Some(CMD_QUAD_IO_READ as u32),
+                dummy: QUAD_IO_READ_DUMMY,
+                address: addr,
+                ..Default::default()
+            }
+        } else {
+            TransferConfig {
+                iwidth: XspiWidth::SING,
+                adwidth: XspiWidth::SING,
+                adsize: AddressSize::_24bit,
+                dwidth: XspiWidth::SING,
+                instruction: Some(self.part.read as u32),
+                dummy: self.part.read_dummy,
+                address: addr,
+                ..Default::default()
+            }
+        }
+    }
+
+    /// Returns `Error::Range` unless the range is within a writable region.
+    pub fn check_writable(&self, addr: u32, len: usize) -> Result<(), Error> {
+        let end = addr.checked_add(len as u32).ok_or(Error::Range)?;
+        if len == 0
+            || self
+                .writable
+                .iter()
+                .any(|r| r.start <= addr && end <= r.end)
+        {
+            Ok(())
+        } else {
+            warn!("Flash write {addr:#x} len {len:#x} not permitted");
+            Err(Error::Range)
+        }
+    }
+
+    /// Starts a sector erase, without waiting for completion.
+    ///
+    /// `addr` must be sector aligned.
+    pub fn start_sector_erase(&mut self, addr: u32) -> Result<(), Error> {
+        if !(addr as usize).is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::Range);
+        }
+        self.check_writable(addr, SECTOR_SIZE)?;
+
+        self.exec_command(CMD_WRITE_ENABLE)?;
+        self.exec_address_command(CMD_SECTOR_ERASE, addr)
+    }
+
+    /// Starts programming within a single page, without waiting
+    /// for completion. Programming can only clear bits.
+    pub fn start_page_program(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if PAGE_SIZE - (addr as usize % PAGE_SIZE) < data.len() {
+            return Err(Error::Range);
+        }
+        self.check_writable(addr, data.len())?;
+
+        let transaction = TransferConfig {
+            iwidth: XspiWidth::SING,
+            adwidth: XspiWidth::SING,
+            adsize: AddressSize::_24bit,
+            dwidth: XspiWidth::SING,
+            instruction: Some(CMD_PAGE_PROGRAM as u32),
+            dummy: DummyCycles::_0,
+            address: Some(addr),
+            ..Default::default()
+        };
+        self.exec_command(CMD_WRITE_ENABLE)?;
+        self.xspi
+            .blocking_write(data, transaction)
+            .map_err(|_| Error::Xspi)
+    }
+
+    /// Erases a `SECTOR_SIZE` sector, blocking until complete.
+    ///
+    /// `addr` must be sector aligned.
+    pub fn sector_erase(&mut self, addr: u32) -> Result<(), Error> {
+        self.start_sector_erase(addr)?;
+        self.wait_write_finish()
+    }
+
+    /// Programs within a single page, blocking until complete.
+    pub fn page_program(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.start_page_program(addr, data)?;
+        self.wait_write_finish()
+    }
+}
+
+/// Splits a write at page boundaries, for `page_program()`.
+pub fn pages(
+    mut addr: u32,
+    mut data: &[u8],
+) -> impl Iterator<Item = (u32, &[u8])> {
+    core::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        let page_left = PAGE_SIZE - (addr as usize % PAGE_SIZE);
+        let (chunk, rest) = data.split_at(data.len().min(page_left));
+        let a = addr;
+        addr += chunk.len() as u32;
+        data = rest;
+        Some((a, chunk))
+    })
+}
@@ -0,0 +1,68 @@
+#!/usr/bin/env python3
+# SPDX-License-Identifier: MIT OR Apache-2.0
+#
+# Copyright (c) 2025 Code Construct
+"""Writes the xspiloader image CRC record for an ELF program.
+
+The CRC-32 covers the file contents of each PT_LOAD segment, in program
+header order, matching the loader.
+
+The record is followed by the image header, with the format version,
+hardware revision and ELF length, checked by the loader before loading.
+"""
+
+import struct
+import sys
+import zlib
+
+MAGIC = b"ICRC"
+HEADER_MAGIC = b"IHDR"
+HEADER_VERSION = 1
+# Nucleo-H7S3L8
+DEFAULT_HW_REV = 1
+PT_LOAD = 1
+
+
+def image_crc(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 1 or elf[5] != 1:
+        raise ValueError("Not a 32-bit little endian ELF file")
+    (phoff,) = struct.unpack_from("<I", elf, 0x1C)
+    phentsize, phnum = struct.unpack_from("<HH", elf, 0x2A)
+
+    crc = 0
+    for i in range(phnum):
+        p_type, p_offset, _, _, p_filesz = struct.unpack_from(
+            "<IIIII", elf, phoff + i * phentsize
+        )
+        if p_type == PT_LOAD and p_filesz > 0:
+            crc = zlib.crc32(elf[p_offset : p_offset + p_filesz], crc)
+    return crc
+
+
+def image_header(elf_len, hw_rev):
+    hdr = HEADER_MAGIC + struct.pack(
+        "<BBHI", HEADER_VERSION, hw_rev, 0xFFFF, elf_len
+    )
+    return hdr + struct.pack("<I", zlib.crc32(hdr))
+
+
+def main():
+    if len(sys.argv) not in (3, 4):
+        print(
+            f"Usage: {sys.argv[0]} program.elf out.crc [hw_rev]",
+            file=sys.stderr,
+        )
+        sys.exit(1)
+    hw_rev = int(sys.argv[3], 0) if len(sys.argv) == 4 else DEFAULT_HW_REV
+
+    with open(sys.argv[1], "rb") as f:
+        elf = f.read()
+    crc = image_crc(elf)
+    with open(sys.argv[2], "wb") as f:
+        f.write(MAGIC + struct.pack("<I", crc))
+        f.write(image_header(len(elf), hw_rev))
+    print(f"CRC {crc:#010x}, hardware revision {hw_rev}, length {len(elf):#x}")
+
+
+if __name__ == "__main__":
+    main()
@@ -1,6 +1,7 @@
-/* Copy of cortex-m link.x using SRAM2 instead. bootloader uses SRAM2
- * for its own storage, so that loaded program content can be written
- * to all other sections */
+/* Copy of cortex-m link.x using LOADER_RAM, near the end of SRAM2, instead.
+ * bootloader uses LOADER_RAM for its own storage, so that loaded
+ * program content can be written to all other sections and the rest
+ * of SRAM2 */
 
 /* # Developer notes
 
@@ -26,6 +27,12 @@
 /* This will be provided by the user (see `memory.x`) or by a Board Support Crate */
 INCLUDE memory.x
 
+/* Between LOG_RAM and PANIC_RAM. Must match LOADER_RAM in main.rs */
+MEMORY
+{
+    LOADER_RAM : ORIGIN = 0x24037F00, LENGTH = 32K
+}
+
 /* # Entry point = reset vector */
 EXTERN(__RESET_VECTOR);
 EXTERN(Reset);
@@ -64,7 +71,7 @@ PROVIDE(__pre_init = DefaultPreInit);
 /* # Sections */
 SECTIONS
 {
-  PROVIDE(_stack_start = ORIGIN(SRAM2) + LENGTH(SRAM2));
+  PROVIDE(_stack_start = ORIGIN(LOADER_RAM) + LENGTH(LOADER_RAM));
 
   /* ## Sections in FLASH */
   /* ### Vector table */
@@ -132,7 +139,7 @@ SECTIONS
     __sdata = .;
     *(.data .data.*);
     . = ALIGN(4); /* 4-byte align the end (VMA) of this section */
-  } > SRAM2 AT>FLASH
+  } > LOADER_RAM AT>FLASH
   /* Allow sections from user `memory.x` injected using `INSERT AFTER .data` to
    * use the .data loading mechanism by pushing __edata. Note: do not change
    * output region or load region in those user sections! */
@@ -167,7 +174,7 @@ SECTIONS
     *(.bss .bss.*);
     *(COMMON); /* Uninitialized C statics */
     . = ALIGN(4); /* 4-byte align the end (VMA) of this section */
-  } > SRAM2
+  } > LOADER_RAM
   /* Allow sections from user `memory.x` injected using `INSERT AFTER .bss` to
    * use the .bss zeroing mechanism by pushing __ebss. Note: do not change
    * output region or load region in those user sections! */
@@ -182,7 +189,7 @@ SECTIONS
     *(.uninit .uninit.*);
     . = ALIGN(4);
     __euninit = .;
-  } > SRAM2
+  } > LOADER_RAM
 
   /* Place the heap right after `.uninit` in RAM */
   PROVIDE(__sheap = __euninit);
@@ -210,9 +217,15 @@ SECTIONS
 ASSERT(ORIGIN(FLASH) % 4 == 0, "
 ERROR(cortex-m-rt): the start of the FLASH region must be 4-byte aligned");
 
-ASSERT(ORIGIN(SRAM2) % 4 == 0, "
+ASSERT(ORIGIN(LOADER_RAM) % 4 == 0, "
 ERROR(cortex-m-rt): the start of the RAM region must be 4-byte aligned");
 
+ASSERT(ORIGIN(LOADER_RAM) == ORIGIN(LOG_RAM) + LENGTH(LOG_RAM), "
+ERROR(xspiloader): LOADER_RAM must start after LOG_RAM");
+
+ASSERT(ORIGIN(LOADER_RAM) + LENGTH(LOADER_RAM) == ORIGIN(PANIC_RAM), "
+ERROR(xspiloader): LOADER_RAM must end at PANIC_RAM");
+
 ASSERT(__sdata % 4 == 0 && __edata % 4 == 0, "
 BUG(cortex-m-rt): .data is not 4-byte aligned");
 
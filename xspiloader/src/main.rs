@@ -3,35 +3,95 @@
  * Copyright (c) 2025 Code Construct
  */
 
-/* "FlashMemory" based on Embassy examples,
- * Licensed as Apache-2.0 or MIT.
- */
 #![no_std]
 #![no_main]
 
 use core::arch::asm;
 use core::cell::RefCell;
+use core::ops::Range;
 
 #[allow(unused)]
 use log::{debug, error, info, trace, warn};
 
 use embassy_executor::Spawner;
 
+use cortex_m::peripheral::DWT;
 use embassy_stm32::Config;
-use embassy_stm32::mode::Blocking;
 use embassy_stm32::pac;
-use embassy_stm32::xspi::{
-    AddressSize, ChipSelectHighTime, DummyCycles, FIFOThresholdLevel, Instance,
-    MemorySize, MemoryType, TransferConfig, WrapSize, Xspi, XspiWidth,
-};
+use embassy_stm32::peripherals::IWDG;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_stm32::xspi::Instance;
 
+use neotron_loader::Source;
 use panic_probe as _;
+use xspiflash::{FlashMemory, crc};
+
+const SECTOR_SIZE: u32 = xspiflash::SECTOR_SIZE as u32;
+
+/// Flash offset of the `BootControl` record, following usbnvme settings.
+const BOOT_CONTROL_OFFSET: u32 = 0x00ff_1000;
+
+/// The loader only writes the `BootControl` flags.
+const WRITABLE: &[Range<u32>] =
+    &[BOOT_CONTROL_OFFSET..BOOT_CONTROL_OFFSET + SECTOR_SIZE];
+
+/// Watchdog timeout while loading, in microseconds.
+///
+/// The worst case load is assumed to be under 1 second: a RAM-limited
+/// image of under 1MB, read at 8MB/s with 64MHz single line SPI.
+/// The watchdog is also kicked between program headers.
+///
+/// The watchdog stays running once the application starts, which must
+/// continue to kick it.
+const LOAD_WATCHDOG_TIMEOUT_US: u32 = 4_000_000;
+
+type Watchdog = IndependentWatchdog<'static, IWDG>;
+
+/// XSPI2 memory-mapped flash, for reading segments.
+const FLASH_MAPPED_BASE: usize = xspiflash::XSPI2_MAPPED_BASE;
+
+/// Attempts for each indirect flash read, to ride out transient XSPI
+/// errors before failing the slot.
+const READ_ATTEMPTS: usize = 3;
 
-const FLASH_SIZE: usize = 32 * 1024 * 1024;
+/// Boot mailbox address, `BOOT_MAILBOX` in memory.x.
+///
+/// Read by usbnvme `stmutil::boot_mailbox()`, the layout must match.
+/// Word 0 is `BOOT_MAILBOX_MAGIC`, word 1 is the `BootReason` in bits 0-7
+/// and the `Slot` in bits 8-15.
+///
+/// Words 2 and 3 are a request from the application to stay in the
+/// loader, `STAY_MAGIC` and its complement. The contents are random at
+/// power on, so a power cycle boots normally.
+const BOOT_MAILBOX: usize = 0x2403_fff0;
+const BOOT_MAILBOX_MAGIC: u32 = u32::from_le_bytes(*b"BOOT");
+const STAY_MAGIC: u32 = u32::from_le_bytes(*b"STAY");
+
+/// RAM used by the loader for data and stack, near the end of SRAM2.
+///
+/// Matches `LOADER_RAM` in link-bootloader.x. SRAM2 before `LOG_RAM` may
+/// be loaded. The final jump to the entry point only uses registers.
+const LOADER_RAM: Range<u32> = 0x2403_7f00..0x2403_ff00;
+
+/// The application's log from the previous boot, preserved across reset.
+///
+/// Matches `LOG_RAM` in memory.x, not to be overwritten by loading.
+const LOG_RAM: Range<u32> = 0x2403_6f00..0x2403_7f00;
+
+const _: () = assert!(LOG_RAM.end == LOADER_RAM.start);
 
-/* Set ITCM/SRAM1 split to 192/0kB, DTCM/SRAM3 to 128/64kB */
-const ITCM_SPLIT: TCMSplit = TCMSplit::Tcm192;
-const DTCM_SPLIT: TCMSplit = TCMSplit::Tcm128;
+/// Default HSI clock
+const CPU_HZ: u32 = 64_000_000;
+
+/// Preferred split, ITCM/SRAM1 192/0kB and DTCM/SRAM3 128/64kB.
+///
+/// Used when the image's segments fit, otherwise a split is chosen to
+/// suit the segments. The application's stack and other unloaded
+/// memory may rely on this split.
+const DEFAULT_SPLIT: TcmSplit = TcmSplit {
+    itcm: TCMSplit::Tcm192,
+    dtcm: TCMSplit::Tcm128,
+};
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
@@ -50,24 +110,19 @@ async fn main(_spawner: Spawner) {
     // Initialize peripherals
     let p = embassy_stm32::init(config);
 
-    set_tcm_split(ITCM_SPLIT, DTCM_SPLIT);
-
-    let qspi_config = embassy_stm32::xspi::Config {
-        fifo_threshold: FIFOThresholdLevel::_4Bytes,
-        memory_type: MemoryType::Macronix,
-        delay_hold_quarter_cycle: true,
-        device_size: MemorySize::_32MiB,
-        chip_select_high_time: ChipSelectHighTime::_2Cycle,
-        free_running_clock: false,
-        clock_mode: false,
-        wrap_size: WrapSize::None,
-        // 64MHz
-        clock_prescaler: 0,
-        sample_shifting: false,
-        chip_select_boundary: 0,
-        max_transfer: 0,
-        refresh: 0,
-    };
+    // Checked before starting the watchdog, which can't be stopped
+    let stay = take_stay_request();
+
+    // Resets if flash access hangs
+    let mut wdg = IndependentWatchdog::new(p.IWDG, LOAD_WATCHDOG_TIMEOUT_US);
+    if !stay {
+        wdg.unleash();
+    }
+
+    // For timing the load
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
 
     let xspi = embassy_stm32::xspi::Xspi::new_blocking_quadspi(
         p.XSPI2,
@@ -77,15 +132,57 @@ async fn main(_spawner: Spawner) {
         p.PN4,
         p.PN5,
         p.PN1,
-        qspi_config,
+        xspiflash::config(),
     );
 
-    let flash = FlashMemory::new(xspi).await;
-    let flash = FlashCell {
-        inner: RefCell::new(flash),
+    let Ok(mut flash) = FlashMemory::new(xspi) else {
+        log::logger().flush();
+        panic!("Flash setup failed");
+    };
+    flash.set_writable(WRITABLE);
+    let quad = flash.quad();
+
+    if stay {
+        info!("Staying in loader on request, reset to boot");
+        log::logger().flush();
+        // Flash and the debug port are left idle for probe-rs
+        core::future::pending::<()>().await;
+    }
+
+    let mut bootctl = BootControl::read(&mut flash);
+    let (mut slot, mut reason) = match &mut bootctl {
+        Some(b) => b.select(&mut flash).await,
+        None => {
+            info!("No boot control record, using slot A");
+            (Slot::A, BootReason::Normal)
+        }
     };
 
-    let entry = load_elf(&flash).await.expect("elf loading failed");
+    let mut flash = RefCell::new(flash);
+    let start = DWT::cycle_count();
+    let loaded = load_slot(&flash, slot, &mut wdg).await;
+    let entry = match loaded {
+        Ok(e) => e,
+        Err(()) => {
+            let other = slot.other();
+            warn!("Slot {slot:?} failed, falling back to slot {other:?}");
+            if let Some(b) = &mut bootctl {
+                b.revert(flash.get_mut()).await;
+            }
+            slot = other;
+            reason = BootReason::Fallback;
+            load_slot(&flash, other, &mut wdg)
+                .await
+                .expect("elf loading failed")
+        }
+    };
+    write_boot_mailbox(reason, slot);
+    let elapsed = DWT::cycle_count().wrapping_sub(start) / (CPU_HZ / 1000);
+    info!(
+        "Loaded in {} ms, {} memory-mapped read",
+        elapsed,
+        if quad { "quad" } else { "single line" }
+    );
 
     // Drop it to disable the XSPI peripheral.
     drop(flash);
@@ -114,8 +211,7 @@ async fn main(_spawner: Spawner) {
 }
 
 /// `TCM` gets this much memory, the `SRAM` gets the rest.
-#[allow(unused)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum TCMSplit {
     Tcm64 = 0b000,
     Tcm128 = 0b001,
@@ -123,16 +219,40 @@ enum TCMSplit {
 }
 
 impl TCMSplit {
+    /// Legal option byte values
+    const ALL: [Self; 3] = [Self::Tcm64, Self::Tcm128, Self::Tcm192];
+
     /// Size in bytes
     fn size(&self) -> usize {
         0x10000 * (*self as usize + 1)
     }
 }
 
+/// ITCM/SRAM1 and DTCM/SRAM3 split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TcmSplit {
+    itcm: TCMSplit,
+    dtcm: TCMSplit,
+}
+
+impl TcmSplit {
+    /// Returns the candidate splits, `DEFAULT_SPLIT` first.
+    fn candidates() -> impl Iterator<Item = Self> {
+        let all = TCMSplit::ALL.into_iter().flat_map(|itcm| {
+            TCMSplit::ALL
+                .into_iter()
+                .map(move |dtcm| Self { itcm, dtcm })
+        });
+        core::iter::once(DEFAULT_SPLIT)
+            .chain(all.filter(|s| *s != DEFAULT_SPLIT))
+    }
+}
+
 /// Set persistent ITCM/SRAM1 and DTCM/SRAM3 split.
-fn set_tcm_split(itcm: TCMSplit, dtcm: TCMSplit) {
+fn set_tcm_split(split: TcmSplit) {
     let regs = pac::FLASH;
 
+    let TcmSplit { itcm, dtcm } = split;
     info!("Using TCM split ITCM {itcm:?} DTCM {dtcm:?}");
 
     let itcm = itcm as u8;
@@ -178,14 +298,15 @@ fn set_tcm_split(itcm: TCMSplit, dtcm: TCMSplit) {
     });
 }
 
-/// Check whether a load address is valid
-fn valid_dest(start: u32, length: u32) -> bool {
-    let dtcm_size = DTCM_SPLIT.size() as u32;
-    let itcm_size = ITCM_SPLIT.size() as u32;
+/// Check whether a load address is valid with a TCM split
+fn valid_dest(start: u32, length: u32, split: TcmSplit) -> bool {
+    let dtcm_size = split.dtcm.size() as u32;
+    let itcm_size = split.itcm.size() as u32;
     let dtcm_start = 0x2000_0000;
     let itcm_start = 0x0000_0000;
     // sram1 start address varies
     let sram1_end = 0x2402_0000;
+    let sram2_start = 0x2402_0000;
     let sram3_start = 0x2404_0000;
     let range = [
         // ITCM/SRAM1 and DTCM/SRAM3 split is configurable, these are upper limits.
@@ -199,7 +320,8 @@ fn valid_dest(start: u32, length: u32) -> bool {
         dtcm_start..(dtcm_start + dtcm_size),
         // SRAM3
         sram3_start..(sram3_start + (0x30000 - dtcm_size)),
-        // SRAM2 is used by xspiloader itself (link-bootloader.x), so disallowed.
+        // SRAM2, except the preserved log and xspiloader itself
+        sram2_start..LOG_RAM.start,
     ];
 
     if length == 0 {
@@ -230,17 +352,371 @@ fn neotron_error<T: core::fmt::Debug>(
     }
 }
 
+/// Reason for booting a slot, reported to the application.
+#[derive(Debug, Clone, Copy)]
+enum BootReason {
+    /// Confirmed or only slot
+    Normal = 0,
+    /// First boot of a newly activated slot
+    Trial = 1,
+    /// The active slot wasn't confirmed, booting the other slot
+    Reverted = 2,
+    /// The selected slot failed to load, booting the other slot
+    Fallback = 3,
+}
+
+fn write_boot_mailbox(reason: BootReason, slot: Slot) {
+    info!("Boot reason {reason:?}");
+    let mb = BOOT_MAILBOX as *mut u32;
+    // Safety: reserved region, not used by xspiloader or the application.
+    unsafe {
+        mb.add(1)
+            .write_volatile(reason as u32 | ((slot as u32) << 8));
+        mb.write_volatile(BOOT_MAILBOX_MAGIC);
+    }
+}
+
+/// Reads and clears a request from the application to stay in the loader.
+fn take_stay_request() -> bool {
+    let mb = BOOT_MAILBOX as *mut u32;
+    // Safety: reserved region, not used by xspiloader or the application
+    // other than the mailbox.
+    let (magic, check) = unsafe {
+        let m = mb.add(2).read_volatile();
+        let c = mb.add(3).read_volatile();
+        mb.add(2).write_volatile(0);
+        mb.add(3).write_volatile(0);
+        (m, c)
+    };
+    magic == STAY_MAGIC && check == !STAY_MAGIC
+}
+
+/// Flash image slot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::A),
+            1 => Some(Self::B),
+            _ => None,
+        }
+    }
+
+    /// Flash range of the slot.
+    ///
+    /// The slot not booted is the usbnvme staging region, written by PLDM
+    /// file transfer.
+    fn region(&self) -> Range<u32> {
+        match self {
+            Self::A => 0x0000_0000..0x0080_0000,
+            Self::B => 0x0080_0000..0x00ff_0000,
+        }
+    }
+
+    fn other(&self) -> Self {
+        match self {
+            Self::A => Self::B,
+            Self::B => Self::A,
+        }
+    }
+}
+
+/// Selects the image slot to boot.
+///
+/// On-flash format, 9 bytes at `BOOT_CONTROL_OFFSET`:
+///
+/// | Offset | Field                                         |
+/// |--------|-----------------------------------------------|
+/// | 0      | magic `"BCTL"`                                |
+/// | 4      | version, 1                                    |
+/// | 5      | active slot, 0 for A, 1 for B                 |
+/// | 6      | attempted, 0x00 once the loader has tried it  |
+/// | 7      | confirmed, 0x00 once the application confirms |
+/// | 8      | reverted, 0x00 once the loader falls back     |
+///
+/// A new image is activated by writing the record with the flag bytes
+/// as 0xff. Each flag is later programmed to 0x00 without an erase.
+/// An image that is attempted but not confirmed by the next reset is
+/// reverted to the other slot.
+struct BootControl {
+    active: Slot,
+    attempted: bool,
+    confirmed: bool,
+    reverted: bool,
+}
+
+impl BootControl {
+    const MAGIC: u32 = u32::from_le_bytes(*b"BCTL");
+    const VERSION: u8 = 1;
+    const LEN: usize = 9;
+
+    const OFF_ATTEMPTED: u32 = 6;
+    const OFF_REVERTED: u32 = 8;
+
+    /// Reads the record, `None` if blank or invalid.
+    fn read<I: Instance>(flash: &mut FlashMemory<I>) -> Option<Self> {
+        let mut buf = [0u8; Self::LEN];
+        if let Err(e) = flash.read(BOOT_CONTROL_OFFSET, &mut buf) {
+            warn!("Boot control read failed: {e:?}");
+            return None;
+        }
+
+        let magic = u32::from_le_bytes(buf[..4].try_into().unwrap());
+        if magic != Self::MAGIC || buf[4] != Self::VERSION {
+            // Includes blank 0xff flash
+            return None;
+        }
+        let Some(active) = Slot::from_u8(buf[5]) else {
+            warn!("Bad boot control slot {}", buf[5]);
+            return None;
+        };
+        Some(Self {
+            active,
+            attempted: buf[6] == 0,
+            confirmed: buf[7] == 0,
+            reverted: buf[8] == 0,
+        })
+    }
+
+    /// Returns the slot to boot, recording an attempt or a revert.
+    async fn select<I: Instance>(
+        &mut self,
+        flash: &mut FlashMemory<I>,
+    ) -> (Slot, BootReason) {
+        let active = self.active;
+        if self.reverted {
+            info!("Slot {active:?} was reverted");
+            (active.other(), BootReason::Reverted)
+        } else if self.confirmed {
+            info!("Booting confirmed slot {active:?}");
+            (active, BootReason::Normal)
+        } else if !self.attempted {
+            info!("Trying new image in slot {active:?}");
+            Self::set_flag(flash, Self::OFF_ATTEMPTED);
+            self.attempted = true;
+            (active, BootReason::Trial)
+        } else {
+            warn!("Slot {active:?} wasn't confirmed, reverting");
+            self.revert(flash).await;
+            (active.other(), BootReason::Reverted)
+        }
+    }
+
+    /// Records that an unconfirmed active slot has been abandoned.
+    ///
+    /// The application won't then confirm it.
+    async fn revert<I: Instance>(&mut self, flash: &mut FlashMemory<I>) {
+        if self.confirmed || self.reverted {
+            return;
+        }
+        Self::set_flag(flash, Self::OFF_REVERTED);
+        self.reverted = true;
+    }
+
+    /// Programs a flag byte to 0x00, without an erase.
+    fn set_flag<I: Instance>(flash: &mut FlashMemory<I>, off: u32) {
+        if let Err(e) = flash.page_program(BOOT_CONTROL_OFFSET + off, &[0]) {
+            warn!("Boot control write failed: {e:?}");
+        }
+    }
+}
+
+/// Loads the ELF image from a slot.
+///
+/// Returns the entry address
+async fn load_slot<I: Instance>(
+    flash: &RefCell<FlashMemory<I>>,
+    slot: Slot,
+    wdg: &mut Watchdog,
+) -> Result<u32, ()> {
+    info!("Loading slot {slot:?}");
+    let source = FlashCell {
+        inner: flash,
+        region: slot.region(),
+    };
+    let image_len = match ImageHeader::read(&source)? {
+        Some(h) => Some(h.check()?),
+        None => {
+            warn!("No image header, hardware revision not checked");
+            None
+        }
+    };
+    load_elf(&source, image_len, wdg).await
+}
+
+/// Optional image header, following the `ImageCrc` record.
+///
+/// Layout, little endian:
+///
+/// - 0: `MAGIC`
+/// - 4: format version, `VERSION`
+/// - 5: hardware revision, `HW_REV`
+/// - 6: reserved, 2 bytes
+/// - 8: ELF image length
+/// - 12: CRC-32 of bytes 0-11
+///
+/// Blank flash (`0xff`) is treated as no header, and the image boots
+/// unchecked. A header with a bad CRC-32 or unknown version is refused.
+struct ImageHeader {
+    version: u8,
+    hw_rev: u8,
+    image_len: u32,
+}
+
+impl ImageHeader {
+    const MAGIC: u32 = u32::from_le_bytes(*b"IHDR");
+    /// Offset within the last sector of a slot
+    const OFFSET: u32 = 8;
+    const LEN: usize = 16;
+
+    const VERSION: u8 = 1;
+    /// Nucleo-H7S3L8. Images built for other hardware are refused.
+    const HW_REV: u8 = 1;
+
+    /// Reads the header, `None` if not present.
+    ///
+    /// Returns `Err` if a header is present but corrupt.
+    fn read<I: Instance>(source: &FlashCell<I>) -> Result<Option<Self>, ()> {
+        let offset = source.len() - SECTOR_SIZE + Self::OFFSET;
+        let mut buf = [0u8; Self::LEN];
+        if source.read(offset, &mut buf).is_err() {
+            return Ok(None);
+        }
+        let word =
+            |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+
+        // Blank flash reads as 0xff
+        if word(0) != Self::MAGIC {
+            return Ok(None);
+        }
+        let mut crc = crc::Crc32::new();
+        crc.update(&buf[..12]);
+        if crc.finalize() != word(12) {
+            error!("Image header CRC mismatch");
+            return Err(());
+        }
+        Ok(Some(Self {
+            version: buf[4],
+            hw_rev: buf[5],
+            image_len: word(8),
+        }))
+    }
+
+    /// Returns the image length if the image may be booted.
+    fn check(&self) -> Result<u32, ()> {
+        if self.version != Self::VERSION {
+            error!(
+                "Image header version {} unsupported, expected {}",
+                self.version,
+                Self::VERSION
+            );
+            return Err(());
+        }
+        if self.hw_rev != Self::HW_REV {
+            error!(
+                "Image built for hardware revision {}, this is {}",
+                self.hw_rev,
+                Self::HW_REV
+            );
+            return Err(());
+        }
+        info!(
+            "Image header OK, hardware revision {} length {:#x}",
+            self.hw_rev, self.image_len
+        );
+        Ok(self.image_len)
+    }
+}
+
+/// Optional CRC of the loaded image, in the last sector of a slot.
+///
+/// The CRC-32 covers the file contents of each `PT_LOAD` segment,
+/// in program header order.
+struct ImageCrc {
+    magic: u32,
+    crc: u32,
+}
+
+impl ImageCrc {
+    const MAGIC: u32 = u32::from_le_bytes(*b"ICRC");
+
+    /// Reads the CRC record, `None` if not present.
+    fn read<I: Instance>(source: &FlashCell<I>) -> Option<Self> {
+        let offset = source.len() - SECTOR_SIZE;
+        let mut buf = [0u8; 8];
+        source.read(offset, &mut buf).ok()?;
+        let (m, c) = buf.split_at(4);
+        let rec = Self {
+            magic: u32::from_le_bytes(m.try_into().unwrap()),
+            crc: u32::from_le_bytes(c.try_into().unwrap()),
+        };
+        // Blank flash reads as 0xff
+        (rec.magic == Self::MAGIC).then_some(rec)
+    }
+}
+
+/// Returns the TCM split that all `PT_LOAD` segments fit, preferring
+/// `DEFAULT_SPLIT`.
+///
+/// Segments are checked with their memory size, so zero-initialised
+/// data must fit too. Returns `Err` if no single split fits them all,
+/// since programming the split part way through loading would move
+/// memory under segments already loaded.
+fn choose_tcm_split<S: Source>(
+    loader: &neotron_loader::Loader<S>,
+) -> Result<TcmSplit, ()> {
+    // Unreadable headers fail later, in load_elf()
+    let fits = |split: TcmSplit| {
+        loader.iter_program_headers().flatten().all(|ph| {
+            ph.p_type() != neotron_loader::ProgramHeader::PT_LOAD
+                || valid_dest(ph.p_paddr(), ph.p_memsz(), split)
+        })
+    };
+
+    let Some(split) = TcmSplit::candidates().find(|s| fits(*s)) else {
+        error!("Segments don't fit any TCM split");
+        let segments = loader
+            .iter_program_headers()
+            .flatten()
+            .filter(|ph| ph.p_type() == neotron_loader::ProgramHeader::PT_LOAD);
+        for ph in segments {
+            error!("  0x{:x} len 0x{:x}", ph.p_paddr(), ph.p_memsz());
+        }
+        return Err(());
+    };
+    if split != DEFAULT_SPLIT {
+        warn!("Image needs TCM split {split:?}");
+    }
+    Ok(split)
+}
+
 /// Loads an elf image.
 ///
+/// The TCM split is programmed to suit the segments before loading.
+/// The image is verified against an `ImageCrc` record, if present.
+/// Segments must be within `image_len` from an `ImageHeader`, if present.
+///
 /// Returns the entry address
-async fn load_elf(
-    source: impl neotron_loader::Source + Copy,
+async fn load_elf<I: Instance>(
+    source: &FlashCell<'_, I>,
+    image_len: Option<u32>,
+    wdg: &mut Watchdog,
 ) -> Result<u32, ()> {
+    let mut crc = crc::Crc32::new();
     let loader = neotron_loader::Loader::new(source).map_err(|e| {
         warn!("ELF loader failed: {}", neotron_error(&e));
     })?;
 
+    let split = choose_tcm_split(&loader)?;
+    set_tcm_split(split);
+
     for (idx, ph) in loader.iter_program_headers().enumerate() {
+        wdg.pet();
         let Ok(ph) = ph else {
             warn!("program header {} failed", idx);
             return Err(());
@@ -259,10 +735,19 @@ async fn load_elf(
             // Flush in case it faults
             log::logger().flush();
 
-            if !valid_dest(ph.p_paddr(), ph.p_filesz()) {
+            if !valid_dest(ph.p_paddr(), ph.p_filesz(), split) {
                 error!("Invalid dest");
                 return Err(());
             }
+            let in_image = image_len.is_none_or(|l| {
+                ph.p_offset()
+                    .checked_add(ph.p_filesz())
+                    .is_some_and(|end| end <= l)
+            });
+            if !in_image {
+                error!("Segment beyond image length");
+                return Err(());
+            }
 
             let (foff, addr, sz) = if ph.p_paddr() != 0 {
                 (ph.p_offset(), ph.p_paddr(), ph.p_filesz())
@@ -285,6 +770,7 @@ async fn load_elf(
                         zero = in(reg) 0,
                     );
                 }
+                crc.update(&[b]);
 
                 (ph.p_offset() + 1, ph.p_paddr() + 1, ph.p_filesz() - 1)
             };
@@ -293,8 +779,11 @@ async fn load_elf(
             let dest: &mut [u8] =
                 unsafe { core::slice::from_raw_parts_mut(dest, sz as usize) };
 
-            match source.read(foff, dest) {
-                Ok(()) => info!("loaded {}", idx),
+            match source.read_segment(foff, dest) {
+                Ok(()) => {
+                    crc.update(dest);
+                    info!("loaded {}", idx)
+                }
                 Err(_) => {
                     error!("Failed reading");
                     return Err(());
@@ -305,95 +794,75 @@ async fn load_elf(
         }
     }
 
+    let crc = crc.finalize();
+    match ImageCrc::read(source) {
+        Some(expect) if expect.crc == crc => info!("Image CRC {crc:#010x} OK"),
+        Some(expect) => {
+            error!(
+                "Image CRC mismatch, {crc:#010x} expected {:#010x}",
+                expect.crc
+            );
+            return Err(());
+        }
+        None => warn!("No image CRC record, not verified"),
+    }
+
     let entry = loader.e_entry();
     info!("Entry address 0x{:x}", entry);
     Ok(entry)
 }
 
-const CMD_READ: u8 = 0x0B;
-const CMD_ENABLE_RESET: u8 = 0x66;
-const CMD_RESET: u8 = 0x99;
-const CMD_READ_SR: u8 = 0x05;
-
-/// Implementation of access to flash chip.
-/// Chip commands are hardcoded as it depends on used chip.
-pub struct FlashMemory<I: Instance> {
-    xspi: Xspi<'static, I, Blocking>,
+// neotron_loader only passes const references, so wrap it in RefCell
+// Reads are relative to the start of `region`.
+struct FlashCell<'a, I: Instance> {
+    inner: &'a RefCell<FlashMemory<I>>,
+    region: Range<u32>,
 }
 
-impl<I: Instance> FlashMemory<I> {
-    pub async fn new(xspi: Xspi<'static, I, Blocking>) -> Self {
-        let mut memory = Self { xspi };
-        memory.reset_memory().await;
-        memory
-    }
-
-    async fn exec_command(&mut self, cmd: u8) {
-        let transaction = TransferConfig {
-            iwidth: XspiWidth::SING,
-            adwidth: XspiWidth::NONE,
-            // adsize: AddressSize::_24bit,
-            dwidth: XspiWidth::NONE,
-            instruction: Some(cmd as u32),
-            address: None,
-            dummy: DummyCycles::_0,
-            ..Default::default()
-        };
-        self.xspi.blocking_command(&transaction).unwrap();
-    }
-
-    pub async fn reset_memory(&mut self) {
-        self.exec_command(CMD_ENABLE_RESET).await;
-        self.exec_command(CMD_RESET).await;
-        self.wait_write_finish();
+impl<I: Instance> FlashCell<'_, I> {
+    fn len(&self) -> u32 {
+        self.region.end - self.region.start
     }
 
-    pub fn read_memory(&mut self, addr: u32, buffer: &mut [u8]) {
-        let transaction = TransferConfig {
-            iwidth: XspiWidth::SING,
-            adwidth: XspiWidth::SING,
-            adsize: AddressSize::_24bit,
-            dwidth: XspiWidth::SING,
-            instruction: Some(CMD_READ as u32),
-            dummy: DummyCycles::_8,
-            address: Some(addr),
-            ..Default::default()
-        };
-        self.xspi.blocking_read(buffer, transaction).unwrap();
-    }
-
-    fn wait_write_finish(&mut self) {
-        while (self.read_sr() & 0x01) != 0 {}
-    }
+    /// Reads a segment using memory-mapped flash, faster than `read()`.
+    ///
+    /// Falls back to indirect reads if memory-mapping fails.
+    fn read_segment(&self, offset: u32, buffer: &mut [u8]) -> Result<(), ()> {
+        let in_region = (offset as usize)
+            .checked_add(buffer.len())
+            .is_some_and(|end| end <= self.len() as usize);
+        if !in_region {
+            error!("Bad read {:#x} len {:#x}", offset, buffer.len());
+            return Err(());
+        }
 
-    fn read_register(&mut self, cmd: u8) -> u8 {
-        let mut buffer = [0; 1];
-        let transaction: TransferConfig = TransferConfig {
-            iwidth: XspiWidth::SING,
-            isize: AddressSize::_8bit,
-            adwidth: XspiWidth::NONE,
-            adsize: AddressSize::_24bit,
-            dwidth: XspiWidth::SING,
-            instruction: Some(cmd as u32),
-            address: None,
-            dummy: DummyCycles::_0,
-            ..Default::default()
+        let start = (self.region.start + offset) as usize;
+        let mut flash = self.inner.borrow_mut();
+        // Safety: XSPI2 is the only instance, see `main()`
+        let r = unsafe {
+            flash.read_mapped(FLASH_MAPPED_BASE, |m| {
+                let src = m.get(start..start + buffer.len())?;
+                buffer.copy_from_slice(src);
+                Some(())
+            })
         };
-        self.xspi.blocking_read(&mut buffer, transaction).unwrap();
-        buffer[0]
-    }
+        drop(flash);
 
-    pub fn read_sr(&mut self) -> u8 {
-        self.read_register(CMD_READ_SR)
+        match r {
+            Ok(Some(())) => Ok(()),
+            Ok(None) => {
+                error!("Bad read {:#x} len {:#x}", offset, buffer.len());
+                Err(())
+            }
+            Err(e) => {
+                warn!("Memory-mapped read failed: {e:?}, reading indirect");
+                self.read(offset, buffer)
+            }
+        }
     }
 }
 
-// neotron_loader only passes const references, so wrap it in RefCell
-struct FlashCell<I: Instance> {
-    inner: RefCell<FlashMemory<I>>,
-}
-
-impl<I: Instance> neotron_loader::Source for &FlashCell<I> {
+impl<I: Instance> Source for &FlashCell<'_, I> {
     type Error = ();
 
     fn read(&self, offset: u32, buffer: &mut [u8]) -> Result<(), ()> {
@@ -402,12 +871,31 @@ impl<I: Instance> neotron_loader::Source for &FlashCell<I> {
             return Err(());
         };
 
-        if end > FLASH_SIZE {
+        let mut flash = self.inner.borrow_mut();
+        if end > self.len() as usize
+            || (self.region.start as usize + end) > flash.size()
+        {
             error!("Bad read {:#x} len {:#x}", offset, buffer.len());
             return Err(());
         }
 
-        self.inner.borrow_mut().read_memory(offset, buffer);
-        Ok(())
+        let addr = self.region.start + offset;
+        for attempt in 1..=READ_ATTEMPTS {
+            match flash.read(addr, buffer) {
+                Ok(()) => return Ok(()),
+                Err(e @ xspiflash::Error::Xspi) => {
+                    warn!(
+                        "Flash read {addr:#x} len {:#x} failed: {e:?}, attempt {attempt}",
+                        buffer.len()
+                    );
+                }
+                Err(e) => {
+                    error!("Flash read {addr:#x} failed: {e:?}");
+                    return Err(());
+                }
+            }
+        }
+        error!("Flash read {addr:#x} failed after {READ_ATTEMPTS} attempts");
+        Err(())
     }
 }